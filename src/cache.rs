@@ -1,61 +1,831 @@
+use clap::ValueEnum;
 use serde::de::DeserializeOwned;
+use serde::{Deserialize, Serialize};
+use time::{Duration, OffsetDateTime};
 
-use crate::unicamp::{Class, Semester, Subject};
+use crate::unicamp::{Class, Prerequisites, Semester, Subject};
+use serde_yaml::Value;
 use std::cmp::Eq;
-use std::collections::HashMap;
-use std::fs::File;
+use std::collections::{BTreeSet, HashMap};
+use std::fmt;
+use std::fs::{self, File};
 use std::hash::Hash;
-use std::io::prelude::*;
+use std::io::{self, prelude::*};
 use std::path::{Path, PathBuf};
+use std::process;
 
-pub fn load_classes(dir: &str, subject: &Subject, semester: &Semester) -> Option<Vec<Class>> {
+/// Version of the cache file layout, bumped on incompatible changes. See `migrate`.
+pub const CACHE_VERSION: u64 = 2;
+
+/// Layout of every cache file: a version header and the entries.
+#[derive(Serialize)]
+struct CacheFile<'a, T> {
+    version: u64,
+    entries: &'a T,
+}
+
+#[derive(Debug, PartialEq)]
+pub enum CacheError {
+    /// The file exists but could not be read.
+    Read { path: PathBuf, reason: String },
+    /// The file was read but its contents are not a cache file.
+    Corrupt { path: PathBuf, reason: String },
+    /// The file could not be written.
+    Write { path: PathBuf, reason: String },
+}
+
+impl CacheError {
+    fn read(path: &Path, reason: impl ToString) -> Self {
+        CacheError::Read {
+            path: path.to_path_buf(),
+            reason: reason.to_string(),
+        }
+    }
+
+    fn corrupt(path: &Path, reason: impl ToString) -> Self {
+        CacheError::Corrupt {
+            path: path.to_path_buf(),
+            reason: reason.to_string(),
+        }
+    }
+}
+
+impl fmt::Display for CacheError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            CacheError::Read { path, reason } => {
+                write!(
+                    f,
+                    "Could not read cache file {}: {}",
+                    path.display(),
+                    reason
+                )
+            }
+            CacheError::Corrupt { path, reason } => write!(
+                f,
+                "Corrupt cache file {}: {}. Delete it to scrape its subjects again",
+                path.display(),
+                reason
+            ),
+            CacheError::Write { path, reason } => {
+                write!(
+                    f,
+                    "Could not write cache file {}: {}",
+                    path.display(),
+                    reason
+                )
+            }
+        }
+    }
+}
+
+impl std::error::Error for CacheError {}
+
+/// Whether a subject is offered in a semester, as far as the DAC website tells.
+#[derive(Clone, Copy, Debug, Default, Deserialize, PartialEq, Serialize)]
+#[serde(rename_all = "kebab-case")]
+pub enum Offering {
+    /// The page of the subject lists its classes.
+    #[default]
+    Offered,
+    /// There is no page for the subject, or it lists no classes.
+    NotOffered,
+    /// The page could not be fetched, so whether the subject is offered is not known.
+    Unknown,
+}
+
+impl fmt::Display for Offering {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.write_str(match self {
+            Offering::Offered => "offered",
+            Offering::NotOffered => "not-offered",
+            Offering::Unknown => "unknown",
+        })
+    }
+}
+
+impl Offering {
+    /// Reads the names written by `Display`.
+    pub fn from_name(name: &str) -> Option<Self> {
+        [Offering::Offered, Offering::NotOffered, Offering::Unknown]
+            .into_iter()
+            .find(|offering| offering.to_string() == name)
+    }
+}
+
+/// Classes of a subject in a semester, with when and where they were scraped from.
+#[derive(Clone, Debug, Deserialize, PartialEq, Serialize)]
+#[serde(from = "EntryRepr")]
+pub struct ClassesEntry {
+    #[serde(with = "time::serde::rfc3339::option")]
+    pub scraped_at: Option<OffsetDateTime>,
+    pub source: Option<String>,
+    pub offering: Offering,
+    /// Why the page could not be fetched, when the offering is unknown.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub error: Option<String>,
+    pub classes: Vec<Class>,
+}
+
+impl ClassesEntry {
+    /// An entry scraped just now from `source`.
+    pub fn scraped(source: &str, offering: Offering, classes: Vec<Class>) -> Self {
+        ClassesEntry {
+            scraped_at: Some(OffsetDateTime::now_utc().replace_nanosecond(0).unwrap()),
+            source: Some(source.to_string()),
+            offering,
+            error: None,
+            classes,
+        }
+    }
+}
+
+/// On-disk representation of a cache entry. Caches written before entries were timestamped
+/// hold the bare list of classes, which never counts as fresh unless the TTL is infinite.
+/// Entries written before offerings were told apart are offered if they have classes and
+/// unknown otherwise, since an empty list may have been an outage.
+#[derive(Deserialize)]
+#[serde(untagged)]
+enum EntryRepr {
+    Classes(Vec<Class>),
+    Full {
+        #[serde(default, with = "time::serde::rfc3339::option")]
+        scraped_at: Option<OffsetDateTime>,
+        #[serde(default)]
+        source: Option<String>,
+        #[serde(default)]
+        offering: Option<Offering>,
+        #[serde(default)]
+        error: Option<String>,
+        classes: Vec<Class>,
+    },
+}
+
+impl From<EntryRepr> for ClassesEntry {
+    fn from(repr: EntryRepr) -> Self {
+        let legacy_offering = |classes: &Vec<Class>| {
+            if classes.is_empty() {
+                Offering::Unknown
+            } else {
+                Offering::Offered
+            }
+        };
+        match repr {
+            EntryRepr::Classes(classes) => ClassesEntry {
+                scraped_at: None,
+                source: None,
+                offering: legacy_offering(&classes),
+                error: None,
+                classes,
+            },
+            EntryRepr::Full {
+                scraped_at,
+                source,
+                offering,
+                error,
+                classes,
+            } => ClassesEntry {
+                scraped_at,
+                source,
+                offering: offering.unwrap_or_else(|| legacy_offering(&classes)),
+                error,
+                classes,
+            },
+        }
+    }
+}
+
+#[derive(Clone, Copy, Debug, Default, PartialEq, ValueEnum)]
+pub enum CacheMode {
+    /// Scrape what is missing or stale
+    #[default]
+    Normal,
+    /// Scrape everything again
+    Refresh,
+    /// Never scrape, using stale entries as they are
+    Offline,
+}
+
+/// When cached classes may be used instead of scraping them again.
+#[derive(Clone, Debug)]
+pub struct CachePolicy {
+    pub mode: CacheMode,
+    /// How long classes of the current and later semesters stay fresh. Past semesters do not
+    /// change anymore and stay fresh forever.
+    pub ttl: Duration,
+    /// TTLs replacing the above, keyed by semester.
+    pub overrides: HashMap<Semester, Duration>,
+    /// How long a failed fetch is remembered before trying again, in any semester.
+    pub error_ttl: Duration,
+}
+
+impl Default for CachePolicy {
+    fn default() -> Self {
+        CachePolicy {
+            mode: CacheMode::Normal,
+            ttl: Duration::hours(24),
+            overrides: HashMap::new(),
+            error_ttl: Duration::hours(1),
+        }
+    }
+}
+
+impl CachePolicy {
+    /// How long classes of `semester` stay fresh as of `now`, `None` meaning forever.
+    pub fn ttl(&self, semester: &Semester, now: OffsetDateTime) -> Option<Duration> {
+        if let Some(ttl) = self.overrides.get(semester) {
+            return Some(*ttl);
+        }
+        match Semester::from(now.date()).index_from(semester) {
+            Some(idx) if idx > 0 => None,
+            _ => Some(self.ttl),
+        }
+    }
+
+    /// Whether `entry` of `semester` may be used as of `now`.
+    pub fn is_fresh(&self, entry: &ClassesEntry, semester: &Semester, now: OffsetDateTime) -> bool {
+        match self.mode {
+            CacheMode::Refresh => false,
+            CacheMode::Offline => true,
+            CacheMode::Normal if entry.offering == Offering::Unknown => entry
+                .scraped_at
+                .is_some_and(|scraped_at| now - scraped_at < self.error_ttl),
+            CacheMode::Normal => match (self.ttl(semester, now), entry.scraped_at) {
+                (None, _) => true,
+                (Some(ttl), Some(scraped_at)) => now - scraped_at < ttl,
+                (Some(_), None) => false,
+            },
+        }
+    }
+}
+
+/// Where scraped data is cached.
+pub trait Store {
+    fn load_classes(
+        &self,
+        subject: &Subject,
+        semester: &Semester,
+    ) -> Result<Option<ClassesEntry>, CacheError>;
+    fn load_credits(&self, subject: &Subject) -> Result<Option<u8>, CacheError>;
+    fn load_prerequisites(&self, subject: &Subject) -> Result<Option<Prerequisites>, CacheError>;
+    /// Caches `entry` as the classes of `subject` in `semester`.
+    fn save_classes(
+        &self,
+        subject: &Subject,
+        semester: &Semester,
+        entry: &ClassesEntry,
+    ) -> Result<(), CacheError>;
+    fn save_credits(&self, subject: &Subject, credits: u8) -> Result<(), CacheError>;
+    fn save_prerequisites(
+        &self,
+        subject: &Subject,
+        prerequisites: &Prerequisites,
+    ) -> Result<(), CacheError>;
+    /// Everything cached.
+    fn contents(&self) -> Result<CacheContents, CacheError>;
+    /// Caches everything in `contents`, replacing what is already cached for the same
+    /// subjects and semesters.
+    fn import(&self, contents: &CacheContents) -> Result<(), CacheError>;
+    /// Removes the classes of `subject` in `semester`, or of every subject or semester when
+    /// `None`. Removing every semester of a subject also removes its credits and prerequisites.
+    /// Returns how many subjects in a semester were removed.
+    fn remove(
+        &self,
+        subject: Option<&str>,
+        semester: Option<&Semester>,
+    ) -> Result<usize, CacheError>;
+}
+
+/// One `<CODE>.yaml` file of classes by semester per subject, plus `credits.yaml` and
+/// `prerequisites.yaml` for every subject.
+pub struct YamlStore {
+    pub dir: PathBuf,
+}
+
+impl YamlStore {
+    pub fn new(dir: &Path) -> Self {
+        YamlStore {
+            dir: dir.to_path_buf(),
+        }
+    }
+}
+
+impl Store for YamlStore {
+    fn load_classes(
+        &self,
+        subject: &Subject,
+        semester: &Semester,
+    ) -> Result<Option<ClassesEntry>, CacheError> {
+        load_classes(&self.dir, subject, semester)
+    }
+
+    fn load_credits(&self, subject: &Subject) -> Result<Option<u8>, CacheError> {
+        load_credits(&self.dir, subject)
+    }
+
+    fn load_prerequisites(&self, subject: &Subject) -> Result<Option<Prerequisites>, CacheError> {
+        load_prerequisites(&self.dir, subject)
+    }
+
+    fn save_classes(
+        &self,
+        subject: &Subject,
+        semester: &Semester,
+        entry: &ClassesEntry,
+    ) -> Result<(), CacheError> {
+        save_classes(&self.dir, subject, semester, entry)
+    }
+
+    fn save_credits(&self, subject: &Subject, credits: u8) -> Result<(), CacheError> {
+        save_credits(&self.dir, subject, credits)
+    }
+
+    fn save_prerequisites(
+        &self,
+        subject: &Subject,
+        prerequisites: &Prerequisites,
+    ) -> Result<(), CacheError> {
+        save_prerequisites(&self.dir, subject, prerequisites)
+    }
+
+    fn contents(&self) -> Result<CacheContents, CacheError> {
+        load_all(&self.dir)
+    }
+
+    fn import(&self, contents: &CacheContents) -> Result<(), CacheError> {
+        fs::create_dir_all(&self.dir).map_err(|e| CacheError::Write {
+            path: self.dir.clone(),
+            reason: e.to_string(),
+        })?;
+        let mut cached = load_all(&self.dir)?;
+        cached.merge(contents.clone());
+        for code in contents.classes.keys() {
+            save_yaml(&self.classes_path(code), &cached.classes[code])?;
+        }
+        if !contents.credits.is_empty() {
+            save_yaml(&self.dir.join("credits.yaml"), &cached.credits)?;
+        }
+        if !contents.prerequisites.is_empty() {
+            save_yaml(&self.dir.join("prerequisites.yaml"), &cached.prerequisites)?;
+        }
+        Ok(())
+    }
+
+    fn remove(
+        &self,
+        subject: Option<&str>,
+        semester: Option<&Semester>,
+    ) -> Result<usize, CacheError> {
+        let mut cached = load_all(&self.dir)?;
+        let mut removed = 0;
+        for (code, entries) in cached.classes.iter_mut() {
+            if subject.is_some_and(|subject| subject != code) {
+                continue;
+            }
+            let count = entries.len();
+            entries.retain(|s, _| semester.is_some_and(|semester| semester.to_string() != *s));
+            if entries.len() == count {
+                continue;
+            }
+            removed += count - entries.len();
+            let path = self.classes_path(code);
+            if entries.is_empty() {
+                fs::remove_file(&path).map_err(|e| CacheError::Write {
+                    path,
+                    reason: e.to_string(),
+                })?;
+            } else {
+                save_yaml(&path, entries)?;
+            }
+        }
+        if semester.is_none() {
+            let count = (cached.credits.len(), cached.prerequisites.len());
+            cached
+                .credits
+                .retain(|code, _| subject.is_some_and(|subject| subject != code));
+            cached
+                .prerequisites
+                .retain(|code, _| subject.is_some_and(|subject| subject != code));
+            if cached.credits.len() != count.0 {
+                save_yaml(&self.dir.join("credits.yaml"), &cached.credits)?;
+            }
+            if cached.prerequisites.len() != count.1 {
+                save_yaml(&self.dir.join("prerequisites.yaml"), &cached.prerequisites)?;
+            }
+        }
+        Ok(removed)
+    }
+}
+
+impl YamlStore {
+    fn classes_path(&self, code: &str) -> PathBuf {
+        self.dir.join(code.to_owned() + ".yaml")
+    }
+}
+
+/// Everything in a cache, laid out as in the YAML files.
+#[derive(Clone, Debug, Default, PartialEq)]
+pub struct CacheContents {
+    /// Classes entries by semester, keyed by subject.
+    pub classes: HashMap<String, HashMap<String, ClassesEntry>>,
+    pub credits: HashMap<String, u8>,
+    pub prerequisites: HashMap<String, Prerequisites>,
+}
+
+impl CacheContents {
+    /// Codes of the subjects anything is cached of.
+    pub fn subjects(&self) -> BTreeSet<&str> {
+        self.classes
+            .keys()
+            .chain(self.credits.keys())
+            .chain(self.prerequisites.keys())
+            .map(|code| code.as_str())
+            .collect()
+    }
+
+    /// Adds `other`, replacing entries of the same subjects and semesters.
+    pub fn merge(&mut self, other: CacheContents) {
+        for (code, entries) in other.classes {
+            self.classes.entry(code).or_default().extend(entries);
+        }
+        self.credits.extend(other.credits);
+        self.prerequisites.extend(other.prerequisites);
+    }
+
+    /// Name and text of each YAML file holding these contents.
+    pub fn to_files(&self) -> Vec<(String, String)> {
+        let mut files = self
+            .classes
+            .iter()
+            .map(|(code, entries)| (code.to_owned() + ".yaml", to_yaml(entries)))
+            .collect::<Vec<_>>();
+        files.push(("credits.yaml".to_string(), to_yaml(&self.credits)));
+        files.push((
+            "prerequisites.yaml".to_string(),
+            to_yaml(&self.prerequisites),
+        ));
+        files
+    }
+
+    /// Reads the contents of YAML files given by path and text. Files not named like cache
+    /// files are ignored, and those holding invalid subject codes or semesters are corrupt.
+    pub fn from_files(
+        files: impl IntoIterator<Item = (PathBuf, String)>,
+    ) -> Result<Self, CacheError> {
+        let mut contents = CacheContents::default();
+        for (path, text) in files {
+            let Some(name) = path.file_stem().and_then(|stem| stem.to_str()) else {
+                continue;
+            };
+            if path.extension().is_none_or(|ext| ext != "yaml") || name.starts_with('.') {
+                continue;
+            }
+            let invalid = |what: &str, value: &str| {
+                CacheError::corrupt(&path, format!("invalid {} {:?}", what, value))
+            };
+            // Credits and prerequisites are keyed by subject code too
+            let check_codes = |codes: Vec<&String>| {
+                codes
+                    .into_iter()
+                    .find(|code| !is_subject_code(code))
+                    .map_or(Ok(()), |code| Err(invalid("subject code", code)))
+            };
+            match name {
+                "credits" => {
+                    contents.credits = parse_yaml(&text, &path)?;
+                    check_codes(contents.credits.keys().collect())?;
+                }
+                "prerequisites" => {
+                    contents.prerequisites = parse_yaml(&text, &path)?;
+                    check_codes(contents.prerequisites.keys().collect())?;
+                }
+                code => {
+                    if !is_subject_code(code) {
+                        return Err(invalid("subject code", code));
+                    }
+                    let entries: HashMap<String, ClassesEntry> = parse_yaml(&text, &path)?;
+                    if let Some(semester) = entries.keys().find(|s| Semester::parse(s).is_none()) {
+                        return Err(invalid("semester", semester));
+                    }
+                    contents.classes.insert(code.to_string(), entries);
+                }
+            }
+        }
+        Ok(contents)
+    }
+}
+
+/// Whether `code` looks like a subject code, e.g. "MC102" or "F 128", and so is safe to name a
+/// cache file after.
+fn is_subject_code(code: &str) -> bool {
+    code.chars().next().is_some_and(|c| c.is_ascii_alphabetic())
+        && code.chars().all(|c| c.is_ascii_alphanumeric() || c == ' ')
+}
+
+/// Reads everything cached in the YAML files of `dir`.
+pub fn load_all(dir: &Path) -> Result<CacheContents, CacheError> {
+    let files = match fs::read_dir(dir) {
+        Ok(files) => files,
+        Err(e) if e.kind() == io::ErrorKind::NotFound => return Ok(CacheContents::default()),
+        Err(e) => return Err(CacheError::read(dir, e)),
+    };
+    let mut texts = vec![];
+    for file in files {
+        let path = file.map_err(|e| CacheError::read(dir, e))?.path();
+        if path.is_file() && path.extension().is_some_and(|ext| ext == "yaml") {
+            let text = fs::read_to_string(&path).map_err(|e| CacheError::read(&path, e))?;
+            texts.push((path, text));
+        }
+    }
+    CacheContents::from_files(texts)
+}
+
+fn load_classes(
+    dir: &Path,
+    subject: &Subject,
+    semester: &Semester,
+) -> Result<Option<ClassesEntry>, CacheError> {
     let path = Path::new(dir).join(Path::new((subject.code.to_owned() + ".yaml").as_str()));
-    let cached: HashMap<String, Vec<Class>> = load_yaml(&path).unwrap_or(HashMap::new());
-    cached
-        .get(&semester.to_string())
-        .map(|classes| classes.to_vec())
+    let mut cached: HashMap<String, ClassesEntry> = load_yaml(&path)?;
+    Ok(cached.remove(&semester.to_string()))
 }
 
-pub fn load_credits(dir: &str, subject: &Subject) -> Option<u8> {
+fn load_credits(dir: &Path, subject: &Subject) -> Result<Option<u8>, CacheError> {
     let path = Path::new(dir).join(Path::new("credits.yaml"));
-    let cached: HashMap<String, u8> = load_yaml(&path).unwrap_or(HashMap::new());
-    cached.get(subject.code).copied()
+    let cached: HashMap<String, u8> = load_yaml(&path)?;
+    Ok(cached.get(subject.code).copied())
 }
 
+fn load_prerequisites(dir: &Path, subject: &Subject) -> Result<Option<Prerequisites>, CacheError> {
+    let path = Path::new(dir).join(Path::new("prerequisites.yaml"));
+    let mut cached: HashMap<String, Prerequisites> = load_yaml(&path)?;
+    Ok(cached.remove(subject.code))
+}
+
+/// Reads the entries of a cache file, upgrading older layouts. A missing file has no entries.
 fn load_yaml<K: DeserializeOwned + Eq + Hash, V: DeserializeOwned>(
-    path: &PathBuf,
-) -> Option<HashMap<K, V>> {
-    let mut file = match File::open(path) {
-        Ok(f) => f,
-        Err(_) => {
-            return None;
-        }
+    path: &Path,
+) -> Result<HashMap<K, V>, CacheError> {
+    let text = match fs::read_to_string(path) {
+        Ok(text) => text,
+        Err(e) if e.kind() == io::ErrorKind::NotFound => return Ok(HashMap::new()),
+        Err(e) => return Err(CacheError::read(path, e)),
+    };
+    parse_yaml(&text, path)
+}
+
+/// Reads the entries of the cache file at `path`, whose contents are `text`.
+fn parse_yaml<K: DeserializeOwned + Eq + Hash, V: DeserializeOwned>(
+    text: &str,
+    path: &Path,
+) -> Result<HashMap<K, V>, CacheError> {
+    let value: Value = serde_yaml::from_str(text).map_err(|e| CacheError::corrupt(path, e))?;
+    let entries = migrate(value).map_err(|reason| CacheError::corrupt(path, reason))?;
+    serde_yaml::from_value(entries).map_err(|e| CacheError::corrupt(path, e))
+}
+
+/// Text of a cache file holding `entries`, with the current version header.
+fn to_yaml<K: Serialize + Eq + Hash, V: Serialize>(entries: &HashMap<K, V>) -> String {
+    serde_yaml::to_string(&CacheFile {
+        version: CACHE_VERSION,
+        entries,
+    })
+    .unwrap()
+}
+
+/// Returns the entries of a cache file in any known layout:
+///
+/// 1. a bare map of entries, written before cache files were versioned;
+/// 2. `version: 2` and the map of entries under `entries`.
+///
+/// Older entry formats within the map are read by their types, see `ClassesEntry` and `Class`.
+fn migrate(value: Value) -> Result<Value, String> {
+    let Value::Mapping(mut file) = value else {
+        return Err("expected a mapping".to_string());
+    };
+    let version = match file.get("version") {
+        None => 1,
+        Some(version) => version
+            .as_u64()
+            .ok_or(format!("invalid version {:?}", version))?,
     };
-    let mut text = String::new();
-    if file.read_to_string(&mut text).is_err() {
-        return None;
+    match version {
+        1 => Ok(Value::Mapping(file)),
+        2 => file.remove("entries").ok_or("missing entries".to_string()),
+        _ => Err(format!(
+            "version {} is newer than the supported {}",
+            version, CACHE_VERSION
+        )),
+    }
+}
+
+/// Writes `entries` with the current version header to a temporary file and renames it over
+/// `path`, so that an interrupted run never leaves a half-written cache file.
+fn save_yaml<K: Serialize + Eq + Hash, V: Serialize>(
+    path: &Path,
+    entries: &HashMap<K, V>,
+) -> Result<(), CacheError> {
+    let text = to_yaml(entries);
+    let tmp = path.with_file_name(format!(
+        ".{}.{}.tmp",
+        path.file_name().unwrap().to_string_lossy(),
+        process::id()
+    ));
+    let write = || -> io::Result<()> {
+        fs::create_dir_all(path.parent().unwrap())?;
+        let mut file = File::create(&tmp)?;
+        file.write_all(text.as_bytes())?;
+        file.sync_all()?;
+        fs::rename(&tmp, path)
     };
-    serde_yaml::from_str(&text).unwrap_or(None)
+    write().map_err(|e| {
+        let _ = fs::remove_file(&tmp);
+        CacheError::Write {
+            path: path.to_path_buf(),
+            reason: e.to_string(),
+        }
+    })
 }
 
-pub fn save_credits(dir: &str, subject: &Subject, credits: u8) {
+fn save_credits(dir: &Path, subject: &Subject, credits: u8) -> Result<(), CacheError> {
     let path = Path::new(dir).join(Path::new("credits.yaml"));
-    let mut cached: HashMap<String, u8> = load_yaml(&path).unwrap_or(HashMap::new());
-    if !cached.contains_key(subject.code) {
-        cached.insert(subject.code.to_string(), credits);
-        File::create(path)
-            .unwrap()
-            .write_all(serde_yaml::to_string(&cached).unwrap().as_bytes())
-            .unwrap();
-    }
+    let mut cached: HashMap<String, u8> = load_yaml(&path)?;
+    cached.insert(subject.code.to_string(), credits);
+    save_yaml(&path, &cached)
 }
 
-pub fn save_classes(dir: &str, subject: &Subject, semester: &Semester, classes: &[Class]) {
+fn save_prerequisites(
+    dir: &Path,
+    subject: &Subject,
+    prerequisites: &Prerequisites,
+) -> Result<(), CacheError> {
+    let path = Path::new(dir).join(Path::new("prerequisites.yaml"));
+    let mut cached: HashMap<String, Prerequisites> = load_yaml(&path)?;
+    cached.insert(subject.code.to_string(), prerequisites.clone());
+    save_yaml(&path, &cached)
+}
+
+fn save_classes(
+    dir: &Path,
+    subject: &Subject,
+    semester: &Semester,
+    entry: &ClassesEntry,
+) -> Result<(), CacheError> {
     let path = Path::new(dir).join(Path::new((subject.code.to_owned() + ".yaml").as_str()));
-    let mut cached: HashMap<String, Vec<Class>> = load_yaml(&path).unwrap_or(HashMap::new());
-    cached.insert(semester.to_string(), classes.to_owned());
-    File::create(&path)
-        .unwrap()
-        .write_all(serde_yaml::to_string(&cached).unwrap().as_bytes())
+    let mut cached: HashMap<String, ClassesEntry> = load_yaml(&path)?;
+    cached.insert(semester.to_string(), entry.clone());
+    save_yaml(&path, &cached)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use time::macros::datetime;
+
+    fn entry(scraped_at: Option<OffsetDateTime>) -> ClassesEntry {
+        ClassesEntry {
+            scraped_at,
+            source: None,
+            offering: Offering::NotOffered,
+            error: None,
+            classes: vec![],
+        }
+    }
+
+    #[test]
+    fn past_semesters_never_expire() {
+        let now = datetime!(2024-04-10 12:00 UTC);
+        let policy = CachePolicy::default();
+        let fresh = entry(Some(datetime!(2024-04-10 00:00 UTC)));
+        let stale = entry(Some(datetime!(2024-04-01 00:00 UTC)));
+        for semester in ["1s2024", "2s2024"] {
+            let semester = Semester::from(semester);
+            assert!(policy.is_fresh(&fresh, &semester, now));
+            assert!(!policy.is_fresh(&stale, &semester, now));
+            assert!(!policy.is_fresh(&entry(None), &semester, now));
+        }
+        assert!(policy.is_fresh(&entry(None), &Semester::from("2s2023"), now));
+
+        let policy = CachePolicy {
+            overrides: HashMap::from([(Semester::from("2s2023"), Duration::hours(1))]),
+            ..Default::default()
+        };
+        assert!(!policy.is_fresh(&stale, &Semester::from("2s2023"), now));
+    }
+
+    #[test]
+    fn modes_override_ttl() {
+        let now = datetime!(2024-04-10 12:00 UTC);
+        let semester = Semester::from("1s2024");
+        let refresh = CachePolicy {
+            mode: CacheMode::Refresh,
+            ..Default::default()
+        };
+        assert!(!refresh.is_fresh(&entry(Some(now)), &Semester::from("1s2020"), now));
+        let offline = CachePolicy {
+            mode: CacheMode::Offline,
+            ..Default::default()
+        };
+        assert!(offline.is_fresh(&entry(None), &semester, now));
+    }
+
+    #[test]
+    fn failed_fetches_expire_in_every_semester() {
+        let now = datetime!(2024-04-10 12:00 UTC);
+        let policy = CachePolicy::default();
+        let failed = |scraped_at| ClassesEntry {
+            offering: Offering::Unknown,
+            error: Some("HTTP 503".to_string()),
+            ..entry(Some(scraped_at))
+        };
+        for semester in ["2s2023", "1s2024"] {
+            let semester = Semester::from(semester);
+            assert!(policy.is_fresh(&failed(datetime!(2024-04-10 11:30 UTC)), &semester, now));
+            assert!(!policy.is_fresh(&failed(datetime!(2024-04-10 10:00 UTC)), &semester, now));
+        }
+    }
+
+    #[test]
+    fn reads_legacy_entries() {
+        let cached: HashMap<String, ClassesEntry> = serde_yaml::from_str(
+            "1s2024:\n- code: A\n  slots: []\n2s2024:\n  scraped_at: 2024-04-10T12:00:00Z\n  \
+             source: https://example.com\n  classes: []\n",
+        )
         .unwrap();
+        assert_eq!(cached["1s2024"].scraped_at, None);
+        assert_eq!(cached["1s2024"].classes.len(), 1);
+        assert_eq!(
+            cached["2s2024"].scraped_at,
+            Some(datetime!(2024-04-10 12:00 UTC))
+        );
+        assert_eq!(
+            cached["2s2024"].source.as_deref(),
+            Some("https://example.com")
+        );
+        assert_eq!(cached["1s2024"].offering, Offering::Offered);
+        assert_eq!(cached["2s2024"].offering, Offering::Unknown);
+    }
+
+    fn temp_dir(name: &str) -> PathBuf {
+        let dir = std::env::temp_dir().join(format!("unicamp_planner_{}_{}", name, process::id()));
+        fs::create_dir_all(&dir).unwrap();
+        dir
+    }
+
+    const MC102: Subject = Subject::new("IC", "MC102");
+
+    #[test]
+    fn writes_versioned_files() {
+        let dir = temp_dir("versioned");
+        save_credits(&dir, &MC102, 6).unwrap();
+        let text = fs::read_to_string(dir.join("credits.yaml")).unwrap();
+        let credits = load_credits(&dir, &MC102);
+        let leftovers = fs::read_dir(&dir).unwrap().count();
+        fs::remove_dir_all(&dir).unwrap();
+
+        assert_eq!(text, "version: 2\nentries:\n  MC102: 6\n");
+        assert_eq!(credits, Ok(Some(6)));
+        assert_eq!(leftovers, 1);
+    }
+
+    #[test]
+    fn upgrades_unversioned_files() {
+        let dir = temp_dir("unversioned");
+        fs::write(
+            dir.join("MC102.yaml"),
+            "1s2024:\n- - weekday: 2\n    start: 1000\n    finish: 1200\n",
+        )
+        .unwrap();
+        fs::write(dir.join("credits.yaml"), "MC102: 6\n").unwrap();
+        let entry = load_classes(&dir, &MC102, &Semester::from("1s2024"));
+        let credits = load_credits(&dir, &MC102);
+        fs::remove_dir_all(&dir).unwrap();
+
+        let entry = entry.unwrap().unwrap();
+        assert_eq!(entry.scraped_at, None);
+        assert_eq!(entry.classes[0].slots[0].start, 1000);
+        assert_eq!(credits, Ok(Some(6)));
+    }
+
+    #[test]
+    fn names_corrupt_files() {
+        let dir = temp_dir("corrupt");
+        let path = dir.join("credits.yaml");
+        fs::write(&path, "").unwrap();
+        let empty = load_credits(&dir, &MC102);
+        fs::write(&path, "MC102: [6\n").unwrap();
+        let truncated = load_credits(&dir, &MC102);
+        fs::write(&path, "version: 3\nentries: {}\n").unwrap();
+        let newer = load_credits(&dir, &MC102);
+        let overwrite = save_credits(&dir, &MC102, 6);
+        fs::remove_dir_all(&dir).unwrap();
+
+        for result in [empty, truncated, newer] {
+            match result {
+                Err(CacheError::Corrupt { path: p, .. }) => assert_eq!(p, path),
+                other => panic!("expected a corrupt file error, got {:?}", other),
+            }
+        }
+        assert!(overwrite.is_err());
+    }
 }
@@ -0,0 +1,311 @@
+use flate2::read::GzDecoder;
+use flate2::write::GzEncoder;
+use flate2::Compression;
+use itertools::Itertools;
+use std::fs::File;
+use std::io::{self, Read};
+use std::path::{Path, PathBuf};
+use time::{Duration, OffsetDateTime};
+
+use crate::cache::{CacheContents, CacheError, Offering, Store};
+use crate::cli::CacheCommand;
+use crate::unicamp::{Semester, WEEKDAYS};
+
+/// Runs a `cache` subcommand on `store`.
+pub fn run(command: CacheCommand, store: &dyn Store) -> Result<(), CacheError> {
+    match command {
+        CacheCommand::List => list(&store.contents()?),
+        CacheCommand::Show { subject, semester } => show(&store.contents()?, &subject, semester),
+        CacheCommand::Prune { subject, semester } => {
+            let removed = store.remove(subject.as_deref(), semester.as_ref())?;
+            println!("Removed {} cached entries.", removed);
+        }
+        CacheCommand::Export { file } => {
+            let contents = store.contents()?;
+            export(&contents, &file)?;
+            println!(
+                "Exported the cached data of {} subjects to {}.",
+                contents.subjects().len(),
+                file.display()
+            );
+        }
+        CacheCommand::Import { file } => {
+            let contents = import(&file)?;
+            store.import(&contents)?;
+            println!(
+                "Imported the cached data of {} subjects from {}.",
+                contents.subjects().len(),
+                file.display()
+            );
+        }
+    }
+    Ok(())
+}
+
+/// Semesters sorted chronologically.
+fn sorted_semesters<'a>(semesters: impl Iterator<Item = &'a String>) -> Vec<&'a String> {
+    semesters
+        .sorted_by_key(|s| Semester::parse(s).map(|semester| (semester.year, semester.semester)))
+        .collect()
+}
+
+fn list(contents: &CacheContents) {
+    let now = OffsetDateTime::now_utc();
+    println!(
+        "{:<10} {:<10} {:>7} {:>7}  SCRAPED",
+        "SUBJECT", "SEMESTER", "CREDITS", "CLASSES"
+    );
+    for code in contents.subjects() {
+        let credits = contents
+            .credits
+            .get(code)
+            .map_or("?".to_string(), |c| c.to_string());
+        let Some(entries) = contents.classes.get(code) else {
+            println!("{:<10} {:<10} {:>7} {:>7}", code, "-", credits, "-");
+            continue;
+        };
+        for semester in sorted_semesters(entries.keys()) {
+            let entry = &entries[semester];
+            println!(
+                "{:<10} {:<10} {:>7} {:>7}  {}",
+                code,
+                semester,
+                credits,
+                match entry.offering {
+                    Offering::Offered => entry.classes.len().to_string(),
+                    Offering::NotOffered => "-".to_string(),
+                    Offering::Unknown => "?".to_string(),
+                },
+                entry
+                    .scraped_at
+                    .map_or("unknown".to_string(), |t| age(now - t) + " ago")
+            );
+        }
+    }
+}
+
+fn age(age: Duration) -> String {
+    if age.whole_days() > 0 {
+        format!("{} days", age.whole_days())
+    } else if age.whole_hours() > 0 {
+        format!("{} hours", age.whole_hours())
+    } else {
+        format!("{} minutes", age.whole_minutes())
+    }
+}
+
+fn show(contents: &CacheContents, code: &str, semester: Option<Semester>) {
+    if !contents.subjects().contains(code) {
+        println!("Nothing cached for subject {}.", code);
+        return;
+    }
+    println!(
+        "{}: {} credits, prerequisites: {}",
+        code,
+        contents
+            .credits
+            .get(code)
+            .map_or("?".to_string(), |c| c.to_string()),
+        contents
+            .prerequisites
+            .get(code)
+            .map_or("?".to_string(), |p| p.to_string())
+    );
+    let Some(entries) = contents.classes.get(code) else {
+        return;
+    };
+    for s in sorted_semesters(entries.keys()) {
+        if semester
+            .as_ref()
+            .is_some_and(|semester| semester.to_string() != *s)
+        {
+            continue;
+        }
+        let entry = &entries[s];
+        println!(
+            "{} (scraped {}{}):",
+            s,
+            entry
+                .scraped_at
+                .map_or("at an unknown time".to_string(), |t| t.date().to_string()),
+            entry
+                .source
+                .as_ref()
+                .map_or(String::new(), |source| format!(" from {}", source))
+        );
+        match entry.offering {
+            Offering::Offered if entry.classes.is_empty() => println!("  no classes"),
+            Offering::Offered => {}
+            Offering::NotOffered => println!("  not offered"),
+            Offering::Unknown => println!(
+                "  could not be fetched{}",
+                entry
+                    .error
+                    .as_ref()
+                    .map_or(String::new(), |error| format!(": {}", error))
+            ),
+        }
+        for class in entry.classes.iter() {
+            println!("  {}", class);
+            for slot in class.slots.iter() {
+                println!(
+                    "    {} {:0>2}:{:0>2}-{:0>2}:{:0>2}{}",
+                    WEEKDAYS[(slot.weekday - 1) as usize],
+                    slot.start / 100,
+                    slot.start % 100,
+                    slot.finish / 100,
+                    slot.finish % 100,
+                    slot.room
+                        .as_ref()
+                        .map_or(String::new(), |room| format!(" {}", room))
+                );
+            }
+        }
+    }
+}
+
+/// Writes `contents` to `file` as a gzipped tarball of YAML cache files under `cache/`.
+fn export(contents: &CacheContents, file: &Path) -> Result<(), CacheError> {
+    let write = || -> io::Result<()> {
+        let mut archive =
+            tar::Builder::new(GzEncoder::new(File::create(file)?, Compression::default()));
+        let mtime = OffsetDateTime::now_utc().unix_timestamp() as u64;
+        for (name, text) in contents.to_files() {
+            let mut header = tar::Header::new_gnu();
+            header.set_size(text.len() as u64);
+            header.set_mode(0o644);
+            header.set_mtime(mtime);
+            archive.append_data(&mut header, Path::new("cache").join(name), text.as_bytes())?;
+        }
+        archive.into_inner()?.finish()?;
+        Ok(())
+    };
+    write().map_err(|e| CacheError::Write {
+        path: file.to_path_buf(),
+        reason: e.to_string(),
+    })
+}
+
+/// Reads the cache files in a tarball written by `export`.
+fn import(file: &Path) -> Result<CacheContents, CacheError> {
+    let read = || -> io::Result<Vec<(PathBuf, String)>> {
+        let mut archive = tar::Archive::new(GzDecoder::new(File::open(file)?));
+        let mut files = vec![];
+        for entry in archive.entries()? {
+            let mut entry = entry?;
+            if !entry.header().entry_type().is_file() {
+                continue;
+            }
+            let path = file.join(entry.path()?);
+            let mut text = String::new();
+            entry.read_to_string(&mut text)?;
+            files.push((path, text));
+        }
+        Ok(files)
+    };
+    let files = read().map_err(|e| CacheError::Read {
+        path: file.to_path_buf(),
+        reason: e.to_string(),
+    })?;
+    CacheContents::from_files(files)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::cache::{ClassesEntry, YamlStore};
+    use crate::unicamp::{Class, Prerequisites, Slot};
+    use std::collections::HashMap;
+    use std::fs;
+
+    #[test]
+    fn export_import_round_trip() {
+        let dir =
+            std::env::temp_dir().join(format!("unicamp_planner_archive_{}", std::process::id()));
+        let contents = CacheContents {
+            classes: HashMap::from([(
+                "MC102".to_string(),
+                HashMap::from([(
+                    "1s2024".to_string(),
+                    ClassesEntry {
+                        scraped_at: Some(OffsetDateTime::from_unix_timestamp(1712750400).unwrap()),
+                        source: Some("https://example.com".to_string()),
+                        offering: Offering::Offered,
+                        error: None,
+                        classes: vec![Class {
+                            code: "A".to_string(),
+                            slots: vec![Slot::parse("Segunda", "10:00 - 12:00").unwrap()],
+                            ..Default::default()
+                        }],
+                    },
+                )]),
+            )]),
+            credits: HashMap::from([("MC102".to_string(), 6)]),
+            prerequisites: HashMap::from([("MC102".to_string(), Prerequisites::default())]),
+        };
+        let archive = dir.join("cache.tar.gz");
+        let store = YamlStore::new(&dir.join("data"));
+        fs::create_dir_all(&dir).unwrap();
+        export(&contents, &archive).unwrap();
+        store.import(&import(&archive).unwrap()).unwrap();
+        let imported = store.contents();
+        let removed = store.remove(None, Some(&Semester::from("1s2024")));
+        let pruned = store.contents();
+        fs::remove_dir_all(&dir).unwrap();
+
+        assert_eq!(imported, Ok(contents.clone()));
+        assert_eq!(removed, Ok(1));
+        assert_eq!(
+            pruned,
+            Ok(CacheContents {
+                classes: HashMap::new(),
+                ..contents
+            })
+        );
+    }
+
+    #[test]
+    fn import_rejects_invalid_semesters_and_codes() {
+        let dir =
+            std::env::temp_dir().join(format!("unicamp_planner_invalid_{}", std::process::id()));
+        let archive = dir.join("cache.tar.gz");
+        fs::create_dir_all(&dir).unwrap();
+        let entry = ClassesEntry::scraped("saved", Offering::NotOffered, vec![]);
+        let with_classes = |code: &str, semester: &str| CacheContents {
+            classes: HashMap::from([(
+                code.to_string(),
+                HashMap::from([(semester.to_string(), entry.clone())]),
+            )]),
+            ..Default::default()
+        };
+        let mut imported = vec![];
+        for contents in [
+            with_classes("MC102", "fall2024"),
+            with_classes("MC-102", "1s2024"),
+            CacheContents {
+                credits: HashMap::from([("mc 102?".to_string(), 6)]),
+                ..Default::default()
+            },
+        ] {
+            export(&contents, &archive).unwrap();
+            imported.push(import(&archive));
+        }
+        fs::remove_dir_all(&dir).unwrap();
+
+        let reasons = imported
+            .into_iter()
+            .map(|result| match result {
+                Err(CacheError::Corrupt { reason, .. }) => reason,
+                other => panic!("expected a corrupt archive, got {:?}", other),
+            })
+            .collect::<Vec<_>>();
+        assert_eq!(
+            reasons,
+            [
+                "invalid semester \"fall2024\"",
+                "invalid subject code \"MC-102\"",
+                "invalid subject code \"mc 102?\""
+            ]
+        );
+    }
+}
@@ -0,0 +1,245 @@
+use itertools::Itertools;
+use std::collections::HashMap;
+use time::{Date, Duration, Month, OffsetDateTime, Weekday};
+
+use crate::unicamp::{Class, Semester};
+
+/// First and last day of classes of each semester.
+#[derive(Clone, Debug, Default)]
+pub struct AcademicCalendar {
+    /// Dates overriding the built-in ones, keyed by semester (e.g. "1s2024").
+    pub overrides: HashMap<Semester, (Date, Date)>,
+}
+
+impl AcademicCalendar {
+    /// Dates of `semester`, from the overrides or else approximated from the usual Unicamp
+    /// calendar: first semesters run from the first Monday of March to early July, second
+    /// semesters from the first Monday of August to early December.
+    pub fn dates(&self, semester: &Semester) -> (Date, Date) {
+        if let Some(dates) = self.overrides.get(semester) {
+            return *dates;
+        }
+        let year = semester.year as i32;
+        let (start, end) = if semester.semester == 1 {
+            (
+                Date::from_calendar_date(year, Month::March, 1).unwrap(),
+                Date::from_calendar_date(year, Month::July, 8).unwrap(),
+            )
+        } else {
+            (
+                Date::from_calendar_date(year, Month::August, 1).unwrap(),
+                Date::from_calendar_date(year, Month::December, 9).unwrap(),
+            )
+        };
+        (next_weekday(start, Weekday::Monday), end)
+    }
+}
+
+/// Parses a "YYYY-MM-DD" date.
+pub fn parse_date(s: &str) -> Option<Date> {
+    let mut parts = s.trim().splitn(3, '-').map(|x| x.parse::<i32>().ok());
+    let (year, month, day) = (parts.next()??, parts.next()??, parts.next()??);
+    Date::from_calendar_date(
+        year,
+        Month::try_from(u8::try_from(month).ok()?).ok()?,
+        u8::try_from(day).ok()?,
+    )
+    .ok()
+}
+
+fn next_weekday(date: Date, weekday: Weekday) -> Date {
+    let days =
+        (weekday.number_days_from_sunday() + 7 - date.weekday().number_days_from_sunday()) % 7;
+    date + Duration::days(days as i64)
+}
+
+/// Builds an iCalendar file with one weekly event per class slot, repeating from the first to
+/// the last day of `semester`.
+pub fn to_ics(
+    table: &HashMap<&str, Class>,
+    semester: &Semester,
+    calendar: &AcademicCalendar,
+) -> String {
+    let (start, end) = calendar.dates(semester);
+    let now = OffsetDateTime::now_utc();
+    let stamp = format!(
+        "{:04}{:02}{:02}T{:02}{:02}{:02}Z",
+        now.year(),
+        now.month() as u8,
+        now.day(),
+        now.hour(),
+        now.minute(),
+        now.second()
+    );
+    // Campinas has been at UTC-3 all year since 2019, so the last day ends at 02:59:59Z
+    let until = end + Duration::days(1);
+    let mut lines = vec![
+        "BEGIN:VCALENDAR".to_string(),
+        "VERSION:2.0".to_string(),
+        "PRODID:-//unicamp_planner//EN".to_string(),
+        "CALSCALE:GREGORIAN".to_string(),
+        format!("X-WR-CALNAME:{}", escape(&semester.to_string())),
+        "BEGIN:VTIMEZONE".to_string(),
+        "TZID:America/Sao_Paulo".to_string(),
+        "BEGIN:STANDARD".to_string(),
+        "DTSTART:19700101T000000".to_string(),
+        "TZOFFSETFROM:-0300".to_string(),
+        "TZOFFSETTO:-0300".to_string(),
+        "TZNAME:-03".to_string(),
+        "END:STANDARD".to_string(),
+        "END:VTIMEZONE".to_string(),
+    ];
+    for (code, class) in table.iter().sorted_by_key(|(code, _)| **code) {
+        for slot in class.slots.iter() {
+            let weekday = Weekday::Sunday.nth_next(slot.weekday - 1);
+            let day = next_weekday(start, weekday);
+            if day > end {
+                continue;
+            }
+            let summary = if class.code.is_empty() {
+                code.to_string()
+            } else {
+                format!("{} (turma {})", code, class.code)
+            };
+            lines.extend([
+                "BEGIN:VEVENT".to_string(),
+                format!(
+                    "UID:{}-{}-{}-{}-{}@unicamp_planner",
+                    semester, code, class.code, slot.weekday, slot.start
+                ),
+                format!("DTSTAMP:{}", stamp),
+                format!(
+                    "DTSTART;TZID=America/Sao_Paulo:{}T{:04}00",
+                    ics_date(day),
+                    slot.start
+                ),
+                format!("DTEND;TZID=America/Sao_Paulo:{}", ics_end(day, slot.finish)),
+                format!("RRULE:FREQ=WEEKLY;UNTIL={}T025959Z", ics_date(until)),
+                format!("SUMMARY:{}", escape(&summary)),
+            ]);
+            if let Some(room) = &slot.room {
+                lines.push(format!("LOCATION:{}", escape(room)));
+            }
+            if !class.professors.is_empty() {
+                lines.push(format!(
+                    "DESCRIPTION:{}",
+                    escape(&format!("Professors: {}", class.professors.join(", ")))
+                ));
+            }
+            lines.push("END:VEVENT".to_string());
+        }
+    }
+    lines.push("END:VCALENDAR".to_string());
+    lines.iter().map(|line| fold(line)).collect()
+}
+
+/// Splits `line` into lines of at most 75 octets, as RFC 5545 requires, ending with CRLF.
+fn fold(line: &str) -> String {
+    let mut folded = String::new();
+    let mut length = 0;
+    for c in line.chars() {
+        if length + c.len_utf8() > 75 {
+            folded.push_str("\r\n ");
+            length = 1;
+        }
+        folded.push(c);
+        length += c.len_utf8();
+    }
+    folded + "\r\n"
+}
+
+/// Local end time of a class on `day` finishing at `finish`, e.g. 1200, which iCalendar wants
+/// as 00:00 of the next day for classes finishing at 24:00.
+fn ics_end(day: Date, finish: u16) -> String {
+    if finish >= 2400 {
+        format!(
+            "{}T{:04}00",
+            ics_date(day.next_day().unwrap()),
+            finish - 2400
+        )
+    } else {
+        format!("{}T{:04}00", ics_date(day), finish)
+    }
+}
+
+fn ics_date(date: Date) -> String {
+    format!(
+        "{:04}{:02}{:02}",
+        date.year(),
+        date.month() as u8,
+        date.day()
+    )
+}
+
+fn escape(text: &str) -> String {
+    text.replace('\\', "\\\\")
+        .replace(';', "\\;")
+        .replace(',', "\\,")
+        .replace('\n', "\\n")
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::unicamp::Slot;
+
+    #[test]
+    fn weekly_events_span_the_semester() {
+        let class = Class {
+            code: "A".to_string(),
+            professors: vec!["Ana Souza".to_string(), "Bruno Lima".to_string()],
+            slots: vec![Slot {
+                room: Some("CB01".to_string()),
+                ..Slot::parse("Quarta", "08:00 - 10:00").unwrap()
+            }],
+            ..Default::default()
+        };
+        let semester = Semester::from("1s2024");
+        let calendar = AcademicCalendar {
+            overrides: HashMap::from([(
+                Semester::from("1s2024"),
+                (
+                    parse_date("2024-02-26").unwrap(),
+                    parse_date("2024-07-06").unwrap(),
+                ),
+            )]),
+        };
+        let ics = to_ics(&HashMap::from([("MC102", class)]), &semester, &calendar);
+        let lines = ics.split("\r\n").collect::<Vec<_>>();
+        for line in [
+            "DTSTART;TZID=America/Sao_Paulo:20240228T080000",
+            "DTEND;TZID=America/Sao_Paulo:20240228T100000",
+            "RRULE:FREQ=WEEKLY;UNTIL=20240707T025959Z",
+            "SUMMARY:MC102 (turma A)",
+            "LOCATION:CB01",
+            "DESCRIPTION:Professors: Ana Souza\\, Bruno Lima",
+        ] {
+            assert!(lines.contains(&line), "missing {:?} in {}", line, ics);
+        }
+        assert_eq!(lines.iter().filter(|l| **l == "BEGIN:VEVENT").count(), 1);
+    }
+
+    #[test]
+    fn classes_until_midnight_end_the_next_day() {
+        let class = Class {
+            slots: vec![Slot::parse("Sábado", "21:00 - 24:00").unwrap()],
+            ..Default::default()
+        };
+        let semester = Semester::from("2s2024");
+        let ics = to_ics(
+            &HashMap::from([("MC102", class)]),
+            &semester,
+            &AcademicCalendar::default(),
+        );
+        let lines = ics.split("\r\n").collect::<Vec<_>>();
+        assert!(lines.contains(&"DTSTART;TZID=America/Sao_Paulo:20240810T210000"));
+        assert!(lines.contains(&"DTEND;TZID=America/Sao_Paulo:20240811T000000"));
+    }
+
+    #[test]
+    fn default_dates_start_on_monday() {
+        let (start, end) = AcademicCalendar::default().dates(&Semester::from("2s2024"));
+        assert_eq!(start, parse_date("2024-08-05").unwrap());
+        assert_eq!(end, parse_date("2024-12-09").unwrap());
+    }
+}
@@ -1,46 +1,508 @@
-use clap::Parser;
-use std::fs::File;
-use std::io::prelude::*;
+use clap::{ArgGroup, CommandFactory, Parser, Subcommand, ValueEnum};
+use std::fs::{self, create_dir_all};
 use std::path::{Path, PathBuf};
+use std::process;
+use time::{Date, Duration};
 
-use crate::unicamp::Subject;
+use crate::cache::{load_all, CacheError, CacheMode, CachePolicy, Store, YamlStore};
+use crate::calendar::{parse_date, AcademicCalendar};
+use crate::fetching::{DacFetcher, Fetcher, FixtureFetcher};
+use crate::output::Format;
+use crate::plan;
+use crate::scheduler::Scheduler;
+use crate::scoring::Scoring;
+use crate::solving::{ElectiveGroup, SearchLimits};
+use crate::sqlite::SqliteStore;
+use crate::unicamp::{Semester, Slot, Subject, WEEKDAYS};
 
 #[derive(Parser, Debug)]
-#[command(author, version, about, long_about = None)]
+#[command(
+    author,
+    version,
+    about,
+    long_about = None,
+    subcommand_negates_reqs = true
+)]
 struct Args {
-    #[arg(short, long)]
-    semester: String,
+    #[command(subcommand)]
+    command: Option<Command>,
+    /// First semester to plan, e.g. "1s2024"
+    #[arg(short, long, value_parser = parse_semester)]
+    semester: Option<Semester>,
+    /// Plan file: a YAML plan with subjects and settings, see `plan::parse_yaml`, or a list of
+    /// INSTITUTE:CODE[:TURMAS] lines
+    #[arg(long, required = true)]
+    subjects_file: Option<String>,
+    /// Maximum credits in every semester
     #[arg(long)]
-    subjects_file: String,
+    cr_max: Option<u8>,
+    /// Format of the solution files. May be given multiple times
+    #[arg(long = "format", value_enum, default_value = "text")]
+    formats: Vec<Format>,
+    /// First and last day of classes of a semester for calendar exports, e.g.
+    /// "1s2024=2024-02-26:2024-07-06". May be given multiple times
+    #[arg(long = "semester-dates", value_name = "SEMESTER=START:END", value_parser = parse_semester_dates)]
+    semester_dates: Vec<(Semester, (Date, Date))>,
+    /// Maximum number of semesters to plan, 10 unless given here or in the plan file
     #[arg(long)]
-    cr_max: u8,
+    max_semesters: Option<usize>,
+    /// Minimum credits in every semester but the last one, 0 unless given here or in the plan
+    /// file
+    #[arg(long)]
+    cr_min: Option<u8>,
+    /// Credit cap replacing --cr-max in one semester, e.g. "2s2024=12". May be given multiple
+    /// times
+    #[arg(long = "cr-cap", value_name = "SEMESTER=CREDITS", value_parser = parse_cr_cap)]
+    cr_caps: Vec<(Semester, u8)>,
+    /// Scrape every subject again, ignoring the cache
+    #[arg(long, conflicts_with = "offline")]
+    refresh: bool,
+    /// Never scrape, using cached classes however old they are
+    #[arg(long)]
+    offline: bool,
+    /// Hours cached classes of the current and later semesters stay fresh, or of a single
+    /// semester with e.g. "2s2023=0". Past semesters stay fresh forever unless given. May be
+    /// given multiple times
+    #[arg(long = "cache-ttl", value_name = "[SEMESTER=]HOURS", value_parser = parse_cache_ttl)]
+    cache_ttls: Vec<(Option<Semester>, u32)>,
+    /// Hours a page that could not be fetched is remembered before trying again, in any
+    /// semester. Its subject is left out of the plan meanwhile
+    #[arg(long = "error-ttl", value_name = "HOURS", default_value_t = 1)]
+    error_ttl: u32,
+    /// Where scraped data is cached: YAML files in data/ or the data/cache.sqlite3 database.
+    /// A new database is filled with the contents of the YAML files
+    #[arg(long, value_enum, default_value = "yaml", global = true)]
+    store: StoreKind,
+    /// Read saved pages from <DIR>/<semester>/<institute>/<code>.html instead of the DAC website
+    #[arg(long, value_name = "DIR")]
+    fixtures: Option<PathBuf>,
+    /// How many pages to fetch at once
+    #[arg(long, default_value_t = 4, value_parser = clap::value_parser!(u16).range(1..))]
+    jobs: u16,
+    /// Most requests per second made to the DAC website, counting retries
+    #[arg(long, default_value_t = 2, value_parser = clap::value_parser!(u32).range(1..))]
+    rate: u32,
+    /// How many times to retry a page after timeouts and server errors, waiting twice as long
+    /// each time
+    #[arg(long, default_value_t = 3)]
+    retries: u32,
+    /// Time window no class may overlap, e.g. "Terça,Quinta 14:00-18:00" or "* 00:00-10:00"
+    /// for every day. May be given multiple times
+    #[arg(long = "block", value_name = "WINDOW", value_parser = parse_window)]
+    blocked: Vec<Window>,
+    /// Weight of a scoring criterion, e.g. "free-days=2". Criteria: compactness (weight 1
+    /// unless given), free-days, idle-gaps, earliest-start, latest-finish, lunch-break and
+    /// semesters. May be given multiple times
+    #[arg(long = "weight", value_name = "CRITERION=WEIGHT", value_parser = parse_weight)]
+    weights: Vec<(String, f32)>,
+    /// Seconds to search for solutions before settling for the best ones found so far
+    #[arg(long, value_name = "SECONDS", value_parser = parse_time_limit)]
+    time_limit: Option<std::time::Duration>,
+    /// Stop searching after finding this many solutions, keeping the best ones among them
+    #[arg(long, value_name = "N", value_parser = clap::value_parser!(u64).range(1..))]
+    max_solutions: Option<u64>,
 }
 
-pub fn parse() -> (String, Vec<Subject>, u8, PathBuf) {
-    let args = Args::parse();
-    let path = Path::new(args.subjects_file.as_str());
-
-    // Open the path in read-only mode, returns `io::Result<File>`
-    let mut file = File::open(path).unwrap();
-    // Read the file contents into a string, returns `io::Result<usize>`
-    let mut text = String::new();
-    file.read_to_string(&mut text).unwrap();
-
-    let subjects = text
-        .split('\n')
-        .filter(|s| !s.is_empty() && !s.starts_with('#'))
-        .map(|subject| {
-            let mut fc = subject.split(':').take(2).map(|x| x.to_string());
-            Subject {
-                institute: Box::leak(fc.next().unwrap().into_boxed_str()),
-                code: Box::leak(fc.next().unwrap().into_boxed_str()),
+#[derive(Subcommand, Debug)]
+enum Command {
+    /// Manage the cached data scraped from DAC
+    #[command(subcommand)]
+    Cache(CacheCommand),
+}
+
+#[derive(Subcommand, Debug)]
+pub enum CacheCommand {
+    /// List cached subjects and semesters with their age
+    List,
+    /// Print the cached credits, prerequisites and classes of a subject
+    Show {
+        subject: String,
+        /// Only print the classes of this semester
+        #[arg(short, long, value_parser = parse_semester)]
+        semester: Option<Semester>,
+    },
+    /// Delete the cached classes of a subject, of a semester or of a subject in a semester.
+    /// Deleting a subject in every semester also deletes its credits and prerequisites
+    #[command(group(ArgGroup::new("entries").required(true).multiple(true)))]
+    Prune {
+        #[arg(long, group = "entries")]
+        subject: Option<String>,
+        #[arg(long, group = "entries", value_parser = parse_semester)]
+        semester: Option<Semester>,
+    },
+    /// Write the whole cache to a .tar.gz archive
+    Export { file: PathBuf },
+    /// Add the cache in an archive written by `cache export`, replacing the classes of the
+    /// same subjects and semesters
+    Import { file: PathBuf },
+}
+
+/// What to run, as given in the command line.
+pub enum Action {
+    Plan(Box<Config>),
+    Cache {
+        command: CacheCommand,
+        store: Box<dyn Store>,
+    },
+}
+
+pub fn parse_semester(s: &str) -> Result<Semester, String> {
+    Semester::parse(s).ok_or(format!(
+        "invalid semester {:?}, expected e.g. \"1s2024\"",
+        s
+    ))
+}
+
+fn parse_cr_cap(s: &str) -> Result<(Semester, u8), String> {
+    let (semester, credits) = s.split_once('=').ok_or("expected SEMESTER=CREDITS")?;
+    let credits = credits
+        .trim()
+        .parse()
+        .map_err(|_| format!("invalid credits {:?}", credits))?;
+    Ok((parse_semester(semester)?, credits))
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, ValueEnum)]
+enum StoreKind {
+    Yaml,
+    Sqlite,
+}
+
+/// Opens the cache in `data_dir`, importing the YAML files into a new SQLite database.
+fn open_store(kind: StoreKind, data_dir: &Path) -> Result<Box<dyn Store>, CacheError> {
+    match kind {
+        StoreKind::Yaml => Ok(Box::new(YamlStore::new(data_dir))),
+        StoreKind::Sqlite => {
+            create_dir_all(data_dir).map_err(|e| CacheError::Write {
+                path: data_dir.to_path_buf(),
+                reason: e.to_string(),
+            })?;
+            let path = data_dir.join("cache.sqlite3");
+            if path.exists() {
+                return Ok(Box::new(SqliteStore::open(&path)?));
             }
+            let contents = load_all(data_dir)?;
+            // Filled under another name and only then moved into place, so that a failed import
+            // leaves no database behind and is tried again next time
+            let temp = data_dir.join("cache.sqlite3.tmp");
+            let _ = fs::remove_file(&temp);
+            let imported = SqliteStore::open(&temp).and_then(|store| store.import(&contents));
+            if let Err(e) = imported {
+                let _ = fs::remove_file(&temp);
+                return Err(e);
+            }
+            fs::rename(&temp, &path).map_err(|e| CacheError::Write {
+                path: path.clone(),
+                reason: e.to_string(),
+            })?;
+            println!(
+                "Created {} with the cached data of {} subjects.",
+                path.display(),
+                contents.subjects().len()
+            );
+            Ok(Box::new(SqliteStore::open(&path)?))
+        }
+    }
+}
+
+/// Reads pages saved in `fixtures` if given, otherwise from the DAC website.
+fn open_fetcher(fixtures: Option<&Path>) -> Box<dyn Fetcher> {
+    match fixtures {
+        Some(dir) => Box::new(FixtureFetcher::new(dir)),
+        None => Box::new(DacFetcher),
+    }
+}
+
+fn parse_cache_ttl(s: &str) -> Result<(Option<Semester>, u32), String> {
+    let (semester, hours) = match s.split_once('=') {
+        Some((semester, hours)) => (Some(parse_semester(semester)?), hours),
+        None => (None, s),
+    };
+    let hours = hours
+        .trim()
+        .parse()
+        .map_err(|_| format!("invalid hours {:?}", hours))?;
+    Ok((semester, hours))
+}
+
+fn parse_semester_dates(s: &str) -> Result<(Semester, (Date, Date)), String> {
+    let (semester, dates) = s.split_once('=').ok_or("expected SEMESTER=START:END")?;
+    let (start, end) = dates.split_once(':').ok_or("expected SEMESTER=START:END")?;
+    let parse = |date: &str| parse_date(date).ok_or(format!("invalid date {:?}", date));
+    let (start, end) = (parse(start)?, parse(end)?);
+    if start >= end {
+        return Err(format!("{} is not before {}", start, end));
+    }
+    Ok((parse_semester(semester)?, (start, end)))
+}
+
+fn parse_weight(s: &str) -> Result<(String, f32), String> {
+    let (name, weight) = s.split_once('=').ok_or("expected CRITERION=WEIGHT")?;
+    let weight = weight
+        .trim()
+        .parse()
+        .map_err(|_| format!("invalid weight {:?}", weight))?;
+    Ok((name.trim().to_string(), weight))
+}
+
+/// Busy slots of a `--block` option, one per weekday.
+#[derive(Clone, Debug)]
+pub struct Window(pub Vec<Slot>);
+
+fn parse_time_limit(s: &str) -> Result<std::time::Duration, String> {
+    s.parse::<f64>()
+        .ok()
+        .and_then(|secs| std::time::Duration::try_from_secs_f64(secs).ok())
+        .ok_or(format!("expected a number of seconds, found {:?}", s))
+}
+
+pub fn parse_window(s: &str) -> Result<Window, String> {
+    let (weekdays, duration) = s
+        .trim()
+        .split_once(' ')
+        .ok_or("expected weekdays and a time window, e.g. \"Sexta 12:00-18:00\"")?;
+    let weekdays = if weekdays == "*" {
+        WEEKDAYS.to_vec()
+    } else {
+        weekdays.split(',').collect()
+    };
+    weekdays
+        .into_iter()
+        .map(|weekday| {
+            Slot::parse(weekday, duration)
+                .ok_or(format!("invalid time window {:?} {:?}", weekday, duration))
         })
-        .collect::<Vec<_>>();
+        .collect::<Result<_, _>>()
+        .map(Window)
+}
 
-    let out_dir = Path::new("data")
+pub struct Config {
+    pub semester: Semester,
+    pub subjects: Vec<Subject>,
+    pub electives: Vec<ElectiveGroup<'static>>,
+    pub cr_max: u8,
+    pub cr_min: u8,
+    pub cr_caps: Vec<(Semester, u8)>,
+    pub out_dir: PathBuf,
+    pub scheduler: Scheduler,
+    pub blocked: Vec<Slot>,
+    pub scoring: Scoring,
+    pub limits: SearchLimits,
+    pub max_semesters: usize,
+    pub formats: Vec<Format>,
+    pub calendar: AcademicCalendar,
+    pub cache_policy: CachePolicy,
+    pub store: Box<dyn Store>,
+}
+
+pub fn parse() -> Action {
+    let args = Args::parse();
+    let data_dir = Path::new("data");
+    let store = open_store(args.store, data_dir).unwrap_or_else(|e| {
+        eprintln!("{}", e);
+        process::exit(1);
+    });
+    if let Some(Command::Cache(command)) = args.command {
+        return Action::Cache { command, store };
+    }
+
+    // Required unless a subcommand is given
+    let subjects_file = args.subjects_file.unwrap();
+    let plan = plan::load(Path::new(subjects_file.as_str())).unwrap_or_else(|e| {
+        eprintln!("{}", e);
+        process::exit(1);
+    });
+    let missing = |name: &str| -> ! {
+        Args::command()
+            .error(
+                clap::error::ErrorKind::MissingRequiredArgument,
+                format!(
+                    "--{} must be given, here or in the plan file",
+                    name.replace('_', "-")
+                ),
+            )
+            .exit()
+    };
+    let semester = args
+        .semester
+        .or(plan.semester)
+        .unwrap_or_else(|| missing("semester"));
+    let cr_max = args
+        .cr_max
+        .or(plan.cr_max)
+        .unwrap_or_else(|| missing("cr_max"));
+
+    let out_dir = data_dir
         .join("solutions")
-        .join(Path::new(args.subjects_file.as_str()).file_stem().unwrap());
+        .join(Path::new(subjects_file.as_str()).file_stem().unwrap());
+
+    let fetcher = open_fetcher(args.fixtures.as_deref());
+
+    let scoring = Scoring::new(&[plan.weights, args.weights].concat()).unwrap_or_else(|e| {
+        Args::command()
+            .error(clap::error::ErrorKind::InvalidValue, e)
+            .exit()
+    });
+
+    let mut cache_policy = CachePolicy {
+        mode: if args.refresh {
+            CacheMode::Refresh
+        } else if args.offline {
+            CacheMode::Offline
+        } else {
+            CacheMode::Normal
+        },
+        error_ttl: Duration::hours(args.error_ttl.into()),
+        ..Default::default()
+    };
+    for (semester, hours) in args.cache_ttls {
+        match semester {
+            Some(semester) => {
+                cache_policy
+                    .overrides
+                    .insert(semester, Duration::hours(hours.into()));
+            }
+            None => cache_policy.ttl = Duration::hours(hours.into()),
+        }
+    }
+
+    Action::Plan(Box::new(Config {
+        semester,
+        subjects: plan.subjects,
+        electives: plan.electives,
+        cr_max,
+        cr_min: args.cr_min.or(plan.cr_min).unwrap_or(0),
+        cr_caps: [plan.cr_caps, args.cr_caps].concat(),
+        out_dir,
+        scheduler: Scheduler {
+            workers: args.jobs as usize,
+            rate: args.rate,
+            retries: args.retries,
+            ..Scheduler::new(fetcher)
+        },
+        blocked: plan
+            .blocked
+            .into_iter()
+            .chain(args.blocked.into_iter().flat_map(|w| w.0))
+            .collect(),
+        scoring,
+        limits: SearchLimits {
+            time_limit: args.time_limit,
+            max_solutions: args.max_solutions.map(|n| n as usize),
+            ..Default::default()
+        },
+        max_semesters: args.max_semesters.or(plan.max_semesters).unwrap_or(10),
+        formats: args.formats,
+        calendar: AcademicCalendar {
+            overrides: args.semester_dates.into_iter().collect(),
+        },
+        cache_policy,
+        store,
+    }))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn fetches_from_fixtures_when_given() {
+        let subject = Subject::new("IC", "MC102");
+        let semester = Semester::from("1s2024");
+        let dir = Path::new(env!("CARGO_MANIFEST_DIR")).join("tests/fixtures");
+        let fixtures = open_fetcher(Some(&dir));
+        assert_eq!(
+            fixtures.source(&subject, &semester),
+            dir.join("1s2024/IC/MC102.html").display().to_string()
+        );
+        assert!(fixtures.fetch(&subject, &semester).unwrap().is_some());
+        assert_eq!(
+            open_fetcher(None).source(&subject, &semester),
+            "https://www.dac.unicamp.br/portal/caderno-de-horarios/2024/1/S/G/IC/MC102"
+        );
+    }
+
+    #[test]
+    fn imports_yaml_into_sqlite_again_after_a_failure() {
+        let dir = std::env::temp_dir().join(format!("unicamp_planner_store_{}", process::id()));
+        create_dir_all(&dir).unwrap();
+        fs::write(
+            dir.join("credits.yaml"),
+            "version: 2\nentries:\n  MC102: 6\n",
+        )
+        .unwrap();
+        // A directory in the way of the new database makes its creation fail
+        create_dir_all(dir.join("cache.sqlite3.tmp")).unwrap();
+        let failed = open_store(StoreKind::Sqlite, &dir).is_err();
+        let created = dir.join("cache.sqlite3").exists();
+        fs::remove_dir(dir.join("cache.sqlite3.tmp")).unwrap();
+        let credits = open_store(StoreKind::Sqlite, &dir)
+            .and_then(|store| store.load_credits(&Subject::new("IC", "MC102")));
+        let leftovers = fs::read_dir(&dir).unwrap().count();
+        fs::remove_dir_all(&dir).unwrap();
+
+        assert!(failed);
+        assert!(!created);
+        assert_eq!(credits, Ok(Some(6)));
+        assert_eq!(leftovers, 2);
+    }
+
+    #[test]
+    fn parses_block_windows() {
+        let window = parse_window("Terça,Quinta 14:00-18:00").unwrap();
+        assert_eq!(
+            window.0,
+            [
+                Slot::parse("Terça", "14:00 - 18:00").unwrap(),
+                Slot::parse("Quinta", "14:00 - 18:00").unwrap()
+            ]
+        );
+        assert_eq!(parse_window("* 00:00-10:00").unwrap().0.len(), 7);
+        assert!(parse_window("Sexta").is_err());
+        assert!(parse_window("Friday 12:00-18:00").is_err());
+        assert!(parse_window("Sexta 18:00-12:00").is_err());
+    }
+
+    #[test]
+    fn rejects_bad_semester_dates() {
+        let (semester, (start, end)) =
+            parse_semester_dates("1s2024=2024-02-26:2024-07-06").unwrap();
+        assert_eq!(semester, Semester::from("1s2024"));
+        assert!(start < end);
+        assert!(parse_semester_dates("1s24x=2024-02-26:2024-07-06").is_err());
+        assert!(parse_semester_dates("1s2024=2024-07-06:2024-02-26").is_err());
+        assert!(parse_semester_dates("1s2024=2024-02-26:2024-02-26").is_err());
+    }
+
+    #[test]
+    fn rejects_bad_cache_ttls() {
+        assert_eq!(
+            parse_cache_ttl("2s2023=0").unwrap(),
+            (Some(Semester::from("2s2023")), 0)
+        );
+        assert_eq!(parse_cache_ttl("12").unwrap(), (None, 12));
+        for args in [
+            ["unicamp_planner", "--cache-ttl=3s2023=1"],
+            ["unicamp_planner", "--cache-ttl=-1"],
+            ["unicamp_planner", "--error-ttl=-1"],
+        ] {
+            let err = Args::try_parse_from(args).unwrap_err();
+            assert_eq!(err.kind(), clap::error::ErrorKind::ValueValidation);
+        }
+    }
 
-    (args.semester, subjects, args.cr_max, out_dir)
+    #[test]
+    fn store_is_accepted_before_and_after_cache() {
+        for args in [
+            ["unicamp_planner", "--store", "sqlite", "cache", "list"],
+            ["unicamp_planner", "cache", "--store", "sqlite", "list"],
+        ] {
+            let args = Args::try_parse_from(args).unwrap();
+            assert_eq!(args.store, StoreKind::Sqlite);
+            assert!(matches!(
+                args.command,
+                Some(Command::Cache(CacheCommand::List))
+            ));
+        }
+    }
 }
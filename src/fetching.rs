@@ -0,0 +1,134 @@
+use crate::unicamp::{Semester, Subject};
+use reqwest::StatusCode;
+use std::fs;
+use std::io::{self, Read};
+use std::path::{Path, PathBuf};
+
+/// Why a page could not be fetched.
+#[derive(Debug, PartialEq)]
+pub struct FetchError {
+    pub reason: String,
+    /// Whether trying again later may succeed, as with timeouts and 5xx responses.
+    pub transient: bool,
+}
+
+/// Source of caderno de horários pages, shared by the workers of a
+/// [`Scheduler`](crate::scheduler::Scheduler).
+pub trait Fetcher: Sync {
+    /// Returns the page of `subject` in `semester`, or `None` if there is none because the
+    /// subject is not offered.
+    fn fetch(&self, subject: &Subject, semester: &Semester) -> Result<Option<String>, FetchError>;
+
+    /// Where the page of `subject` in `semester` comes from, recorded in the cache.
+    fn source(&self, subject: &Subject, semester: &Semester) -> String;
+}
+
+/// Fetches pages from the live DAC website.
+pub struct DacFetcher;
+
+impl Fetcher for DacFetcher {
+    fn fetch(&self, subject: &Subject, semester: &Semester) -> Result<Option<String>, FetchError> {
+        let mut resp =
+            reqwest::get(self.source(subject, semester).as_str()).map_err(|e| FetchError {
+                reason: e.to_string(),
+                transient: e.is_timeout() || e.is_http(),
+            })?;
+        let status = resp.status();
+        // Only a missing page tells the subject is not offered
+        if status == StatusCode::NOT_FOUND || status == StatusCode::GONE {
+            return Ok(None);
+        }
+        if !status.is_success() {
+            return Err(FetchError {
+                reason: format!("HTTP {}", status),
+                transient: status.is_server_error() || status == StatusCode::TOO_MANY_REQUESTS,
+            });
+        }
+        let mut text = String::new();
+        resp.read_to_string(&mut text).map_err(|e| FetchError {
+            reason: e.to_string(),
+            transient: true,
+        })?;
+        Ok(Some(text))
+    }
+
+    fn source(&self, subject: &Subject, semester: &Semester) -> String {
+        format!(
+            "https://www.dac.unicamp.br/portal/caderno-de-horarios/{}/{}/S/G/{}/{}",
+            semester.year, semester.semester, subject.institute, subject.code,
+        )
+    }
+}
+
+/// Reads pages saved under `<dir>/<semester>/<institute>/<code>.html`.
+pub struct FixtureFetcher {
+    pub dir: PathBuf,
+}
+
+impl FixtureFetcher {
+    pub fn new(dir: &Path) -> Self {
+        FixtureFetcher {
+            dir: dir.to_path_buf(),
+        }
+    }
+
+    pub fn path(&self, subject: &Subject, semester: &Semester) -> PathBuf {
+        self.dir
+            .join(semester.to_string())
+            .join(subject.institute)
+            .join(subject.code.to_owned() + ".html")
+    }
+}
+
+impl Fetcher for FixtureFetcher {
+    fn fetch(&self, subject: &Subject, semester: &Semester) -> Result<Option<String>, FetchError> {
+        let path = self.path(subject, semester);
+        match fs::read_to_string(&path) {
+            Ok(page) => Ok(Some(page)),
+            Err(e) if e.kind() == io::ErrorKind::NotFound => Ok(None),
+            Err(e) => Err(FetchError {
+                reason: format!("{}: {}", path.display(), e),
+                transient: false,
+            }),
+        }
+    }
+
+    fn source(&self, subject: &Subject, semester: &Semester) -> String {
+        self.path(subject, semester).display().to_string()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn fixture_pages_missing_are_not_offered() {
+        let fixtures =
+            FixtureFetcher::new(&Path::new(env!("CARGO_MANIFEST_DIR")).join("tests/fixtures"));
+        let semester = Semester::from("1s2024");
+        let page = fixtures.fetch(&Subject::new("IC", "MC102"), &semester);
+        assert!(page.unwrap().unwrap().contains("MC102"));
+        assert_eq!(
+            fixtures.fetch(&Subject::new("IC", "MC999"), &semester),
+            Ok(None)
+        );
+        assert_eq!(
+            fixtures.fetch(&Subject::new("IC", "MC102"), &Semester::from("2s2024")),
+            Ok(None)
+        );
+    }
+
+    #[test]
+    fn fixture_pages_that_cannot_be_read_fail() {
+        let dir = std::env::temp_dir().join(format!("unicamp_planner_fx_{}", std::process::id()));
+        fs::create_dir_all(dir.join("1s2024/IC/MC102.html")).unwrap();
+        let page = FixtureFetcher::new(&dir)
+            .fetch(&Subject::new("IC", "MC102"), &Semester::from("1s2024"));
+        fs::remove_dir_all(&dir).unwrap();
+
+        let err = page.unwrap_err();
+        assert!(!err.transient);
+        assert!(err.reason.contains("MC102.html"));
+    }
+}
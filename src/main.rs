@@ -1,63 +1,333 @@
 mod cache;
+mod cache_commands;
+mod calendar;
 mod cli;
+mod fetching;
+mod output;
+mod plan;
+mod render;
+mod scheduler;
+mod scoring;
 mod scraping;
 mod solving;
+mod sqlite;
 mod unicamp;
 
+use std::collections::hash_map::Entry;
 use std::collections::HashMap;
-use std::fs::{create_dir_all, File};
-use std::io::Write;
+use std::fs::create_dir_all;
+use std::process;
 // use time::OffsetDateTime;
 
-use crate::scraping::build_timesheet;
-use crate::solving::solve_all;
-use crate::unicamp::{Schedule, Semester, Subject, Timesheet};
+use crate::cache::{CachePolicy, Store};
+use crate::output::write_solution;
+use crate::scheduler::Scheduler;
+use crate::scraping::{build_timesheet, ScrapeError};
+use crate::solving::{solve_all, Constraints, CreditLimits, Goals, SubjectGoal};
+use crate::unicamp::{Prerequisites, Semester, Subject, Timesheet};
 
+/// How many years back to look for the latest published classes of a term.
+const MAX_FALLBACK_YEARS: u16 = 2;
+
+/// Builds a timesheet for each of `semesters`. Once a semester has no classes published, later
+/// ones cannot have any either and are not scraped: they use the classes of the latest published
+/// semester of the same term, looked up once per term.
 fn get_timesheets_and_credits(
-    data_dir: &'static str,
     subjects: Vec<Subject>,
-    semester: &Semester,
-) -> (
-    Timesheet<'static>,
-    Timesheet<'static>,
-    HashMap<&'static str, u8>,
-) {
-    let semester_prev = semester.previous();
-    let mut credits_map: HashMap<&str, u8> = HashMap::new();
-    let mut ts1 = build_timesheet(&subjects, semester, &mut credits_map, data_dir);
-    let mut ts2 = build_timesheet(&subjects, &semester_prev, &mut credits_map, data_dir);
-    ts1.remove_duplicates();
-    ts2.remove_duplicates();
-    (ts1, ts2, credits_map)
+    semesters: &[Semester],
+    store: &dyn Store,
+    scheduler: &Scheduler,
+    cache_policy: &CachePolicy,
+    credits_map: &mut HashMap<&'static str, u8>,
+    prerequisites_map: &mut HashMap<&'static str, Prerequisites>,
+) -> Result<Vec<Timesheet<'static>>, ScrapeError> {
+    let mut built: HashMap<String, Timesheet> = HashMap::new();
+    let mut build = |semester: &Semester| -> Result<Timesheet<'static>, ScrapeError> {
+        let ts = match built.entry(semester.to_string()) {
+            Entry::Occupied(entry) => entry.into_mut(),
+            Entry::Vacant(entry) => entry.insert(build_timesheet(
+                &subjects,
+                semester,
+                credits_map,
+                prerequisites_map,
+                store,
+                scheduler,
+                cache_policy,
+            )?),
+        };
+        Ok(ts.clone())
+    };
+    // Latest semester with published classes of each term, or none if not found
+    let mut latest: HashMap<u8, Option<Semester>> = HashMap::new();
+    // Timesheet of the first semester with no classes published, once found
+    let mut unpublished: Option<Timesheet> = None;
+    let mut timesheets = vec![];
+    for semester in semesters {
+        if unpublished.is_none() {
+            let ts = build(semester)?;
+            if ts.is_published() {
+                latest.insert(semester.semester, Some(semester.clone()));
+                timesheets.push(ts);
+                continue;
+            }
+            // Look back from the semester before it in its term, and from the one just before it
+            // in the other term unless that was planned
+            for start in [semester.previous().previous(), semester.previous()] {
+                if latest.contains_key(&start.semester) {
+                    continue;
+                }
+                let term = start.semester;
+                let mut found = None;
+                let mut source = start;
+                for _ in 0..MAX_FALLBACK_YEARS {
+                    if build(&source)?.is_published() {
+                        found = Some(source.clone());
+                        break;
+                    }
+                    source = source.previous().previous();
+                }
+                latest.insert(term, found);
+            }
+            unpublished = Some(ts);
+        }
+        let ts = match &latest[&semester.semester] {
+            Some(source) => {
+                println!(
+                    "No classes published for {} yet, using those of {}.",
+                    semester, source
+                );
+                build(source)?
+            }
+            None => unpublished.clone().unwrap(),
+        };
+        timesheets.push(ts);
+    }
+    for ts in timesheets.iter_mut() {
+        ts.retain_allowed(&subjects);
+        ts.remove_duplicates();
+    }
+    Ok(timesheets)
 }
 
 fn main() {
-    let data_dir: &'static str = "data";
-    let (semester_str, subjects, cr_max, out_dir) = cli::parse();
+    match cli::parse() {
+        cli::Action::Plan(config) => plan(config),
+        cli::Action::Cache { command, store } => cache_commands::run(command, store.as_ref())
+            .unwrap_or_else(|e| {
+                eprintln!("{}", e);
+                process::exit(1);
+            }),
+    }
+}
+
+fn plan(config: Box<cli::Config>) {
+    let cli::Config {
+        semester,
+        subjects,
+        electives,
+        cr_max,
+        cr_min,
+        cr_caps,
+        out_dir,
+        scheduler,
+        blocked,
+        scoring,
+        limits,
+        max_semesters,
+        formats,
+        calendar,
+        cache_policy,
+        store,
+    } = *config;
     // let mut semester = Semester::from(OffsetDateTime::now_utc().date());
-    let mut semester = Semester::from(semester_str.as_str());
-    let (ts1, ts2, cr_map) = get_timesheets_and_credits(data_dir, subjects, &semester);
-    let mut solutions = solve_all(&ts1, &ts2, &cr_map, cr_max);
-    solutions.sort_by(|a, b| a.score.total_cmp(&b.score));
+    let semesters = std::iter::successors(Some(semester.clone()), |s| Some(s.next()))
+        .take(max_semesters)
+        .collect::<Vec<_>>();
+    let goal_map = subjects
+        .iter()
+        .map(|subject| {
+            let earliest = subject
+                .earliest
+                .as_ref()
+                .map_or(0, |s| s.index_from(&semester).unwrap_or(0));
+            let latest = match &subject.latest {
+                None => usize::MAX,
+                Some(s) => s.index_from(&semester).unwrap_or_else(|| {
+                    eprintln!(
+                        "Latest semester {} of {} is before the first semester {}",
+                        s, subject.code, semester
+                    );
+                    process::exit(1);
+                }),
+            };
+            let goal = SubjectGoal {
+                priority: subject.priority,
+                optional: subject.optional,
+                semesters: earliest..=latest,
+            };
+            (subject.code, goal)
+        })
+        .collect::<HashMap<_, _>>();
+    let goals = Goals {
+        subjects: goal_map,
+        electives,
+    };
+    let mut cr_map = HashMap::new();
+    let mut prerequisites = HashMap::new();
+    let timesheets = get_timesheets_and_credits(
+        subjects,
+        &semesters,
+        store.as_ref(),
+        &scheduler,
+        &cache_policy,
+        &mut cr_map,
+        &mut prerequisites,
+    )
+    .unwrap_or_else(|e| {
+        eprintln!("{}", e);
+        process::exit(1);
+    });
+    let constraints = Constraints {
+        credits: CreditLimits {
+            min: cr_min,
+            max: cr_max,
+            caps: cr_caps
+                .iter()
+                .map(|(s, cap)| match s.index_from(&semester) {
+                    Some(idx) => (idx, *cap),
+                    None => {
+                        eprintln!(
+                            "Credit cap for {} is before the first semester {}",
+                            s, semester
+                        );
+                        process::exit(1);
+                    }
+                })
+                .collect(),
+        },
+        blocked,
+    };
+    let solutions = solve_all(
+        &timesheets,
+        &goals,
+        &cr_map,
+        &prerequisites,
+        &constraints,
+        &scoring,
+        &limits,
+    );
+    if let Some(best) = solutions.first() {
+        for (subject, reason) in best.missing.iter() {
+            let optional = goals
+                .subjects
+                .get(subject)
+                .is_some_and(|goal| goal.optional);
+            println!(
+                "The best solution leaves out {}{}: {}.",
+                subject,
+                if optional { " (optional)" } else { "" },
+                reason
+            );
+        }
+    }
     create_dir_all(&out_dir).unwrap();
-    for (i, solution) in solutions.iter().rev().take(5).enumerate() {
-        let mut file = File::create(out_dir.join(format!("solution_{}.txt", i + 1)))
-            .expect("Could not open solution file");
-        semester = Semester::from(semester_str.as_str()).previous();
-        file.write_all(format!("Score: {}\n", solution.score).as_bytes())
-            .expect("Error while writing solution to file");
-        for schedule in solution.schedules.iter() {
-            semester = semester.next();
-            file.write_all(
-                format!(
-                    "{} ({} credits)\n{}",
-                    semester,
-                    schedule.cr_count,
-                    Schedule::from(&schedule.table)
-                )
-                .as_bytes(),
-            )
-            .expect("Error while writing solution to file");
+    for (i, solution) in solutions.iter().enumerate() {
+        for format in formats.iter() {
+            write_solution(
+                &out_dir,
+                *format,
+                i + 1,
+                solution,
+                &semesters,
+                &scoring,
+                &calendar,
+            );
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::cache::YamlStore;
+    use crate::fetching::{FetchError, Fetcher, FixtureFetcher};
+    use itertools::Itertools;
+    use std::fs;
+    use std::path::Path;
+    use std::sync::{Arc, Mutex};
+
+    /// Reads the test fixtures, recording the semesters of the pages fetched.
+    struct CountingFetcher(FixtureFetcher, Arc<Mutex<Vec<String>>>);
+
+    impl Fetcher for CountingFetcher {
+        fn fetch(
+            &self,
+            subject: &Subject,
+            semester: &Semester,
+        ) -> Result<Option<String>, FetchError> {
+            self.1.lock().unwrap().push(semester.to_string());
+            self.0.fetch(subject, semester)
+        }
+
+        fn source(&self, subject: &Subject, semester: &Semester) -> String {
+            self.0.source(subject, semester)
+        }
+    }
+
+    /// Builds the timesheets of MC102 over `count` semesters from `first`, of which only 1s2024
+    /// is published, along with the semesters fetched, sorted.
+    fn build(first: &str, count: usize) -> (Vec<Semester>, Vec<Timesheet<'static>>, Vec<String>) {
+        let cache_dir = std::env::temp_dir().join(format!(
+            "unicamp_planner_fallback_{}_{}",
+            first,
+            process::id()
+        ));
+        let fixtures =
+            FixtureFetcher::new(&Path::new(env!("CARGO_MANIFEST_DIR")).join("tests/fixtures"));
+        let fetched = Arc::new(Mutex::new(vec![]));
+        let scheduler = Scheduler {
+            rate: 1000,
+            ..Scheduler::new(Box::new(CountingFetcher(fixtures, fetched.clone())))
+        };
+        let semesters = std::iter::successors(Some(Semester::from(first)), |s| Some(s.next()))
+            .take(count)
+            .collect::<Vec<_>>();
+        let timesheets = get_timesheets_and_credits(
+            vec![Subject::new("IC", "MC102")],
+            &semesters,
+            &YamlStore::new(&cache_dir),
+            &scheduler,
+            &CachePolicy::default(),
+            &mut HashMap::new(),
+            &mut HashMap::new(),
+        )
+        .unwrap();
+        fs::remove_dir_all(&cache_dir).unwrap();
+        let fetched = fetched.lock().unwrap().iter().cloned().sorted().collect();
+        (semesters, timesheets, fetched)
+    }
+
+    #[test]
+    fn falls_back_on_the_latest_published_semester_of_each_term() {
+        let (semesters, timesheets, fetched) = build("2s2024", 10);
+        assert_eq!(semesters.last().unwrap().to_string(), "1s2029");
+        for (semester, ts) in semesters.iter().zip(timesheets.iter()) {
+            let classes = ts.table.get("MC102").map_or(0, |classes| classes.len());
+            assert_eq!(
+                classes,
+                if semester.semester == 1 { 2 } else { 0 },
+                "{}",
+                semester
+            );
         }
+        // Only the first semester of the plan is fetched, then the earlier ones of each term
+        assert_eq!(fetched, ["1s2024", "2s2022", "2s2023", "2s2024"]);
+    }
+
+    #[test]
+    fn stops_scraping_after_the_first_unpublished_semester() {
+        let (_, timesheets, fetched) = build("1s2024", 3);
+        assert_eq!(timesheets[2].table["MC102"].len(), 2);
+        assert_eq!(fetched, ["1s2024", "2s2022", "2s2023", "2s2024"]);
     }
 }
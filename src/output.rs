@@ -0,0 +1,461 @@
+use clap::ValueEnum;
+use itertools::Itertools;
+use serde::{Deserialize, Serialize};
+use std::fs::File;
+use std::io::Write;
+use std::path::Path;
+
+use crate::calendar::{to_ics, AcademicCalendar};
+use crate::render::to_html;
+use crate::scoring::Scoring;
+use crate::solving::{Solution, Stop};
+use crate::unicamp::{Class, Schedule, Semester, Slot, WEEKDAYS};
+
+#[derive(Clone, Copy, Debug, PartialEq, ValueEnum)]
+pub enum Format {
+    /// ASCII grids, one `solution_N.txt` per solution
+    Text,
+    /// One `solution_N.json` per solution, see `SolutionDocument`
+    Json,
+    /// One `solution_N_<semester>.ics` calendar per semester with classes
+    Ics,
+    /// One self-contained `solution_N.html` page per solution with an SVG grid per semester
+    Html,
+}
+
+/// Version of the JSON schema, bumped on incompatible changes.
+pub const SCHEMA_VERSION: u32 = 2;
+
+/// JSON document written for each solution with `--format json`:
+///
+/// ```json
+/// {
+///   "version": 2,
+///   "rank": 1,
+///   "score": 3.2,
+///   "breakdown": [{ "criterion": "compactness", "value": 3.2, "weight": 1.0 }],
+///   "semesters": [
+///     {
+///       "semester": "1s2024",
+///       "credits": 6,
+///       "score": 3.2,
+///       "classes": [
+///         {
+///           "subject": "MC102",
+///           "turma": "A",
+///           "professors": ["Ana Souza"],
+///           "vacancies": 60,
+///           "reserved": 10,
+///           "slots": [{ "weekday": "Segunda", "start": "10:00", "finish": "12:00", "room": "CB01" }]
+///         }
+///       ]
+///     }
+///   ]
+/// }
+/// ```
+///
+/// Classes are sorted by subject and unknown values (`turma`, `vacancies`, `reserved`, `room`)
+/// are `null`. Plans with elective groups also list the subjects taken of each, as
+/// `"electives": [{ "group": "Electives", "subjects": ["MC855"] }]`, and solutions leaving out
+/// subjects list them with why, as
+/// `"missing": [{ "subject": "MC322", "reason": "not offered in semesters 1, 2" }]`.
+///
+/// `search` tells how the search went, e.g. `{ "branches": 5120, "pruned": 312, "solutions":
+/// 48, "seconds": 0.8, "stopped": "time-limit", "optimal": false }`. A search stopped by
+/// `--time-limit` or `--max-solutions` may have missed better solutions. Even when `optimal`,
+/// the solutions are only the best among plans taking every subject as soon as it fits.
+#[derive(Debug, Deserialize, PartialEq, Serialize)]
+pub struct SolutionDocument {
+    pub version: u32,
+    pub rank: usize,
+    pub score: f32,
+    pub breakdown: Vec<CriterionDocument>,
+    pub semesters: Vec<SemesterDocument>,
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub electives: Vec<ElectiveDocument>,
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub missing: Vec<MissingDocument>,
+    #[serde(default)]
+    pub search: SearchDocument,
+}
+
+#[derive(Debug, Default, Deserialize, PartialEq, Serialize)]
+pub struct SearchDocument {
+    pub branches: usize,
+    pub pruned: usize,
+    pub solutions: usize,
+    pub seconds: f32,
+    pub stopped: Option<String>,
+    pub optimal: bool,
+}
+
+#[derive(Debug, Deserialize, PartialEq, Serialize)]
+pub struct MissingDocument {
+    pub subject: String,
+    pub reason: String,
+}
+
+#[derive(Debug, Deserialize, PartialEq, Serialize)]
+pub struct ElectiveDocument {
+    pub group: String,
+    pub subjects: Vec<String>,
+}
+
+#[derive(Debug, Deserialize, PartialEq, Serialize)]
+pub struct CriterionDocument {
+    pub criterion: String,
+    pub value: f32,
+    pub weight: f32,
+}
+
+#[derive(Debug, Deserialize, PartialEq, Serialize)]
+pub struct SemesterDocument {
+    pub semester: String,
+    pub credits: u8,
+    pub score: f32,
+    pub classes: Vec<ClassDocument>,
+}
+
+#[derive(Debug, Deserialize, PartialEq, Serialize)]
+pub struct ClassDocument {
+    pub subject: String,
+    pub turma: Option<String>,
+    pub professors: Vec<String>,
+    pub vacancies: Option<u16>,
+    pub reserved: Option<u16>,
+    pub slots: Vec<SlotDocument>,
+}
+
+#[derive(Debug, Deserialize, PartialEq, Serialize)]
+pub struct SlotDocument {
+    pub weekday: String,
+    pub start: String,
+    pub finish: String,
+    pub room: Option<String>,
+}
+
+impl SolutionDocument {
+    pub fn new(
+        rank: usize,
+        solution: &Solution,
+        semesters: &[Semester],
+        scoring: &Scoring,
+    ) -> Self {
+        SolutionDocument {
+            version: SCHEMA_VERSION,
+            rank,
+            score: solution.score,
+            breakdown: solution
+                .breakdown
+                .iter()
+                .map(|(name, value)| CriterionDocument {
+                    criterion: name.to_string(),
+                    value: *value,
+                    weight: scoring.weight(name),
+                })
+                .collect(),
+            semesters: solution
+                .schedules
+                .iter()
+                .zip(semesters)
+                .map(|(schedule, semester)| SemesterDocument {
+                    semester: semester.to_string(),
+                    credits: schedule.cr_count,
+                    score: schedule.score,
+                    classes: schedule
+                        .table
+                        .iter()
+                        .sorted_by_key(|(code, _)| **code)
+                        .map(|(code, class)| ClassDocument::new(code, class))
+                        .collect(),
+                })
+                .collect(),
+            electives: solution
+                .electives
+                .iter()
+                .map(|(group, subjects)| ElectiveDocument {
+                    group: group.clone(),
+                    subjects: subjects.iter().map(|s| s.to_string()).collect(),
+                })
+                .collect(),
+            missing: solution
+                .missing
+                .iter()
+                .map(|(subject, reason)| MissingDocument {
+                    subject: subject.to_string(),
+                    reason: reason.clone(),
+                })
+                .collect(),
+            search: SearchDocument {
+                branches: solution.search.branches,
+                pruned: solution.search.pruned,
+                solutions: solution.search.found,
+                seconds: solution.search.elapsed.as_secs_f32(),
+                stopped: solution.search.stopped.map(|stop| {
+                    match stop {
+                        Stop::TimeLimit => "time-limit",
+                        Stop::MaxSolutions => "max-solutions",
+                    }
+                    .to_string()
+                }),
+                optimal: solution.search.stopped.is_none(),
+            },
+        }
+    }
+}
+
+impl ClassDocument {
+    fn new(subject: &str, class: &Class) -> Self {
+        ClassDocument {
+            subject: subject.to_string(),
+            turma: Some(class.code.clone()).filter(|code| !code.is_empty()),
+            professors: class.professors.clone(),
+            vacancies: class.vacancies,
+            reserved: class.reserved,
+            slots: class.slots.iter().map(SlotDocument::from).collect(),
+        }
+    }
+}
+
+impl From<&Slot> for SlotDocument {
+    fn from(slot: &Slot) -> Self {
+        SlotDocument {
+            weekday: WEEKDAYS[(slot.weekday - 1) as usize].to_string(),
+            start: format!("{:0>2}:{:0>2}", slot.start / 100, slot.start % 100),
+            finish: format!("{:0>2}:{:0>2}", slot.finish / 100, slot.finish % 100),
+            room: slot.room.clone(),
+        }
+    }
+}
+
+/// Writes the `rank`-th solution to `<out_dir>/solution_<rank>.<ext>` in `format`, or to
+/// `<out_dir>/solution_<rank>_<semester>.ics` for each semester with `Format::Ics`.
+pub fn write_solution(
+    out_dir: &Path,
+    format: Format,
+    rank: usize,
+    solution: &Solution,
+    semesters: &[Semester],
+    scoring: &Scoring,
+    calendar: &AcademicCalendar,
+) {
+    match format {
+        Format::Text => write_text(out_dir, rank, solution, semesters, scoring),
+        Format::Json => {
+            let document = SolutionDocument::new(rank, solution, semesters, scoring);
+            File::create(out_dir.join(format!("solution_{}.json", rank)))
+                .expect("Could not open solution file")
+                .write_all(serde_json::to_string_pretty(&document).unwrap().as_bytes())
+                .expect("Error while writing solution to file");
+        }
+        Format::Ics => {
+            for (schedule, semester) in solution.schedules.iter().zip(semesters) {
+                if schedule.table.is_empty() {
+                    continue;
+                }
+                File::create(out_dir.join(format!("solution_{}_{}.ics", rank, semester)))
+                    .expect("Could not open solution file")
+                    .write_all(to_ics(&schedule.table, semester, calendar).as_bytes())
+                    .expect("Error while writing solution to file");
+            }
+        }
+        Format::Html => {
+            File::create(out_dir.join(format!("solution_{}.html", rank)))
+                .expect("Could not open solution file")
+                .write_all(to_html(rank, solution, semesters, scoring).as_bytes())
+                .expect("Error while writing solution to file");
+        }
+    }
+}
+
+fn write_text(
+    out_dir: &Path,
+    rank: usize,
+    solution: &Solution,
+    semesters: &[Semester],
+    scoring: &Scoring,
+) {
+    let mut file = File::create(out_dir.join(format!("solution_{}.txt", rank)))
+        .expect("Could not open solution file");
+    file.write_all(
+        format!(
+            "Score: {} ({})\n",
+            solution.score,
+            solution
+                .breakdown
+                .iter()
+                .map(|(name, value)| format!("{}: {} x {}", name, value, scoring.weight(name)))
+                .join(", ")
+        )
+        .as_bytes(),
+    )
+    .expect("Error while writing solution to file");
+    file.write_all(format!("{}.\n", solution.search).as_bytes())
+        .expect("Error while writing solution to file");
+    if let Some(stop) = solution.search.stopped {
+        file.write_all(format!("Stopped at {}, better solutions may exist.\n", stop).as_bytes())
+            .expect("Error while writing solution to file");
+    }
+    for (group, subjects) in solution.electives.iter() {
+        file.write_all(format!("{}: {}\n", group, subjects.iter().join(", ")).as_bytes())
+            .expect("Error while writing solution to file");
+    }
+    for (subject, reason) in solution.missing.iter() {
+        file.write_all(format!("Left out {}: {}\n", subject, reason).as_bytes())
+            .expect("Error while writing solution to file");
+    }
+    for (schedule, semester) in solution.schedules.iter().zip(semesters) {
+        file.write_all(
+            format!(
+                "{} ({} credits, score {})\n{}",
+                semester,
+                schedule.cr_count,
+                schedule.score,
+                Schedule::from(&schedule.table)
+            )
+            .as_bytes(),
+        )
+        .expect("Error while writing solution to file");
+        for (code, class) in schedule.table.iter().sorted_by_key(|(code, _)| **code) {
+            file.write_all(format!("{}: {}\n", code, class).as_bytes())
+                .expect("Error while writing solution to file");
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::solving;
+    use std::collections::HashMap;
+
+    fn solution() -> Solution<'static> {
+        let class = Class {
+            code: "A".to_string(),
+            professors: vec!["Ana Souza".to_string()],
+            vacancies: Some(60),
+            reserved: None,
+            slots: vec![
+                Slot {
+                    room: Some("CB01".to_string()),
+                    ..Slot::parse("Segunda", "10:00 - 12:00").unwrap()
+                },
+                Slot::parse("Quarta", "08:30 - 10:00").unwrap(),
+            ],
+        };
+        Solution {
+            schedules: vec![
+                solving::Schedule {
+                    table: HashMap::from([("MC102", class)]),
+                    cr_count: 6,
+                    score: 3.2,
+                },
+                solving::Schedule {
+                    table: HashMap::new(),
+                    cr_count: 0,
+                    score: 0f32,
+                },
+            ],
+            score: 1.6,
+            breakdown: vec![("compactness", 1.6)],
+            electives: vec![],
+            missing: vec![],
+            search: solving::SearchStats {
+                branches: 12,
+                pruned: 3,
+                found: 4,
+                elapsed: std::time::Duration::from_millis(250),
+                ..Default::default()
+            },
+        }
+    }
+
+    fn semesters() -> Vec<Semester> {
+        vec![Semester::from("1s2024"), Semester::from("2s2024")]
+    }
+
+    #[test]
+    fn json_round_trip() {
+        let document = SolutionDocument::new(1, &solution(), &semesters(), &Scoring::default());
+        let json = serde_json::to_string(&document).unwrap();
+        let parsed: SolutionDocument = serde_json::from_str(&json).unwrap();
+        assert_eq!(parsed, document);
+
+        let with_electives = Solution {
+            electives: vec![("Electives".to_string(), vec!["MC102"])],
+            missing: vec![("MC202", "not offered in semester 1".to_string())],
+            ..solution()
+        };
+        let document = SolutionDocument::new(1, &with_electives, &semesters(), &Scoring::default());
+        let json = serde_json::to_value(&document).unwrap();
+        assert_eq!(
+            json["electives"],
+            serde_json::json!([{ "group": "Electives", "subjects": ["MC102"] }])
+        );
+        assert_eq!(
+            json["missing"],
+            serde_json::json!([{ "subject": "MC202", "reason": "not offered in semester 1" }])
+        );
+
+        let mut stopped = solution();
+        stopped.search.stopped = Some(Stop::TimeLimit);
+        let document = SolutionDocument::new(1, &stopped, &semesters(), &Scoring::default());
+        let json = serde_json::to_value(&document).unwrap();
+        assert_eq!(json["search"]["stopped"], "time-limit");
+        assert_eq!(json["search"]["optimal"], false);
+    }
+
+    #[test]
+    fn json_schema() {
+        let document = SolutionDocument::new(2, &solution(), &semesters(), &Scoring::default());
+        let json = serde_json::to_value(&document).unwrap();
+        assert_eq!(
+            json,
+            serde_json::json!({
+                "version": 2,
+                "rank": 2,
+                "score": 1.6f32,
+                "breakdown": [{ "criterion": "compactness", "value": 1.6f32, "weight": 1.0 }],
+                "semesters": [
+                    {
+                        "semester": "1s2024",
+                        "credits": 6,
+                        "score": 3.2f32,
+                        "classes": [
+                            {
+                                "subject": "MC102",
+                                "turma": "A",
+                                "professors": ["Ana Souza"],
+                                "vacancies": 60,
+                                "reserved": null,
+                                "slots": [
+                                    {
+                                        "weekday": "Segunda",
+                                        "start": "10:00",
+                                        "finish": "12:00",
+                                        "room": "CB01"
+                                    },
+                                    {
+                                        "weekday": "Quarta",
+                                        "start": "08:30",
+                                        "finish": "10:00",
+                                        "room": null
+                                    }
+                                ]
+                            }
+                        ]
+                    },
+                    { "semester": "2s2024", "credits": 0, "score": 0.0, "classes": [] }
+                ],
+                "search": {
+                    "branches": 12,
+                    "pruned": 3,
+                    "solutions": 4,
+                    "seconds": 0.25,
+                    "stopped": null,
+                    "optimal": true
+                }
+            })
+        );
+    }
+}
@@ -0,0 +1,451 @@
+use serde::de::{self, value::MapAccessDeserializer, MapAccess, Visitor};
+use serde::{Deserialize, Deserializer};
+use std::collections::BTreeMap;
+use std::fmt;
+use std::fs;
+use std::path::{Path, PathBuf};
+
+use crate::cli::{parse_semester, parse_window};
+use crate::solving::ElectiveGroup;
+use crate::unicamp::{Semester, Slot, Subject};
+
+/// A plan file that could not be read.
+#[derive(Debug, PartialEq)]
+pub struct PlanError {
+    pub path: PathBuf,
+    /// Line the error is at, starting from 1, if it is at a single line.
+    pub line: Option<usize>,
+    pub reason: String,
+}
+
+impl fmt::Display for PlanError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self.line {
+            Some(line) => write!(f, "{}:{}: {}", self.path.display(), line, self.reason),
+            None => write!(f, "{}: {}", self.path.display(), self.reason),
+        }
+    }
+}
+
+impl std::error::Error for PlanError {}
+
+/// Subjects to plan and the settings given along with them. Settings left out are taken from
+/// the command line, whose options are added to or replace those given here.
+#[derive(Debug, Default)]
+pub struct Plan {
+    pub subjects: Vec<Subject>,
+    pub semester: Option<Semester>,
+    pub cr_max: Option<u8>,
+    pub cr_min: Option<u8>,
+    pub cr_caps: Vec<(Semester, u8)>,
+    pub max_semesters: Option<usize>,
+    pub blocked: Vec<Slot>,
+    pub weights: Vec<(String, f32)>,
+    /// Groups of which only some subjects must be taken.
+    pub electives: Vec<ElectiveGroup<'static>>,
+}
+
+/// Reads the plan file at `path`: YAML if named `.yaml` or `.yml`, otherwise one
+/// `INSTITUTE:CODE[:TURMAS]` line per subject.
+pub fn load(path: &Path) -> Result<Plan, PlanError> {
+    let text = fs::read_to_string(path).map_err(|e| PlanError {
+        path: path.to_path_buf(),
+        line: None,
+        reason: e.to_string(),
+    })?;
+    if path
+        .extension()
+        .is_some_and(|ext| ext == "yaml" || ext == "yml")
+    {
+        parse_yaml(&text, path)
+    } else {
+        parse_text(&text, path)
+    }
+}
+
+/// Reads a list of subjects, one `INSTITUTE:CODE[:TURMAS]` line each. TURMAS is a comma-separated
+/// list where "A,B" pins turmas A and B and "!C" excludes turma C. Empty lines and lines
+/// starting with '#' are skipped.
+pub fn parse_text(text: &str, path: &Path) -> Result<Plan, PlanError> {
+    let mut subjects = vec![];
+    for (idx, line) in text.lines().enumerate() {
+        let line = line.trim();
+        if line.is_empty() || line.starts_with('#') {
+            continue;
+        }
+        let error = |reason| PlanError {
+            path: path.to_path_buf(),
+            line: Some(idx + 1),
+            reason,
+        };
+        let subject = parse_line(line).map_err(error)?.into_subject(None);
+        if subjects.iter().any(|s: &Subject| s.code == subject.code) {
+            return Err(error(format!("subject {} is listed twice", subject.code)));
+        }
+        subjects.push(subject);
+    }
+    Ok(Plan {
+        subjects,
+        ..Default::default()
+    })
+}
+
+/// Reads a YAML plan, e.g.
+///
+/// ```yaml
+/// semester: 1s2024
+/// cr_max: 24
+/// blocked: ["Sexta 12:00-18:00"]
+/// weights: {free-days: 2}
+/// subjects:
+///   - IC:MC102:A,!B
+///   - {institute: IC, code: MC202, priority: 2, earliest: 2s2024}
+///   - {institute: IC, code: MC322, optional: true}
+/// groups:
+///   - name: Electives
+///     latest: 1s2026
+///     min_count: 1
+///     subjects: [IC:MC855, IC:MC859]
+/// ```
+///
+/// Options of a group apply to the subjects in it that do not give their own. Every subject is
+/// required unless its group sets `min_count` or `min_credits`, in which case only as many of
+/// them as needed to reach both are taken.
+pub fn parse_yaml(text: &str, path: &Path) -> Result<Plan, PlanError> {
+    let repr: PlanRepr = serde_yaml::from_str(text).map_err(|e| PlanError {
+        path: path.to_path_buf(),
+        line: e.location().map(|location| location.line()),
+        reason: e.to_string(),
+    })?;
+    let error = |reason| PlanError {
+        path: path.to_path_buf(),
+        line: None,
+        reason,
+    };
+    let mut subjects: Vec<(Subject, Option<&str>)> = vec![];
+    let listed = repr
+        .subjects
+        .into_iter()
+        .map(|subject| (subject.0, None))
+        .chain(repr.groups.iter().flat_map(|group| {
+            group
+                .subjects
+                .iter()
+                .map(move |subject| (subject.0.clone(), Some(group)))
+        }));
+    for (options, group) in listed {
+        let subject = options.into_subject(group);
+        if let (Some(earliest), Some(latest)) = (&subject.earliest, &subject.latest) {
+            if latest.index_from(earliest).is_none() {
+                return Err(error(format!(
+                    "subject {} has its latest semester {} before its earliest {}",
+                    subject.code, latest, earliest
+                )));
+            }
+        }
+        let group = group.map(|group| group.name.as_str());
+        if let Some((_, other)) = subjects.iter().find(|(s, _)| s.code == subject.code) {
+            let place =
+                |group: Option<&str>| group.map_or("subjects".to_string(), |g| g.to_string());
+            return Err(error(format!(
+                "subject {} is listed twice, in {} and {}",
+                subject.code,
+                place(*other),
+                place(group)
+            )));
+        }
+        subjects.push((subject, group));
+    }
+    let mut electives = vec![];
+    for group in repr.groups.iter() {
+        if group.min_count.is_none() && group.min_credits.is_none() {
+            continue;
+        }
+        let min_count = group.min_count.unwrap_or(0);
+        if min_count > group.subjects.len() {
+            return Err(error(format!(
+                "group {} asks for {} subjects but has only {}",
+                group.name,
+                min_count,
+                group.subjects.len()
+            )));
+        }
+        electives.push(ElectiveGroup {
+            name: group.name.clone(),
+            subjects: group
+                .subjects
+                .iter()
+                .map(|member| {
+                    let (subject, _) = subjects
+                        .iter()
+                        .find(|(s, _)| s.code == member.0.code)
+                        .unwrap();
+                    subject.code
+                })
+                .collect(),
+            min_count,
+            min_credits: group.min_credits.unwrap_or(0),
+        });
+    }
+    Ok(Plan {
+        subjects: subjects.into_iter().map(|(subject, _)| subject).collect(),
+        semester: repr.semester.map(|s| s.0),
+        cr_max: repr.cr_max,
+        cr_min: repr.cr_min,
+        cr_caps: repr
+            .cr_caps
+            .into_iter()
+            .map(|(semester, cap)| (semester.0, cap))
+            .collect(),
+        max_semesters: repr.max_semesters,
+        blocked: repr.blocked.into_iter().flat_map(|w| w.0).collect(),
+        weights: repr.weights.into_iter().collect(),
+        electives,
+    })
+}
+
+#[derive(Deserialize)]
+#[serde(deny_unknown_fields)]
+struct PlanRepr {
+    semester: Option<SemesterRepr>,
+    cr_max: Option<u8>,
+    cr_min: Option<u8>,
+    #[serde(default)]
+    cr_caps: BTreeMap<SemesterRepr, u8>,
+    max_semesters: Option<usize>,
+    #[serde(default)]
+    blocked: Vec<WindowRepr>,
+    #[serde(default)]
+    weights: BTreeMap<String, f32>,
+    #[serde(default)]
+    subjects: Vec<SubjectRepr>,
+    #[serde(default)]
+    groups: Vec<GroupRepr>,
+}
+
+/// Subjects listed together, sharing the options they do not give themselves.
+#[derive(Deserialize)]
+#[serde(deny_unknown_fields)]
+struct GroupRepr {
+    name: String,
+    /// How many of the subjects must be taken, all of them unless this or `min_credits` is set.
+    min_count: Option<usize>,
+    /// How many credits the subjects taken must add up to.
+    min_credits: Option<u16>,
+    priority: Option<u8>,
+    optional: Option<bool>,
+    earliest: Option<SemesterRepr>,
+    latest: Option<SemesterRepr>,
+    subjects: Vec<SubjectRepr>,
+}
+
+#[derive(Clone, Deserialize)]
+#[serde(deny_unknown_fields)]
+struct SubjectOptions {
+    institute: String,
+    code: String,
+    #[serde(default)]
+    pinned: Vec<String>,
+    #[serde(default)]
+    excluded: Vec<String>,
+    priority: Option<u8>,
+    optional: Option<bool>,
+    earliest: Option<SemesterRepr>,
+    latest: Option<SemesterRepr>,
+}
+
+impl SubjectOptions {
+    fn into_subject(self, group: Option<&GroupRepr>) -> Subject {
+        let inherit = |own: Option<SemesterRepr>,
+                       of_group: fn(&GroupRepr) -> &Option<SemesterRepr>| {
+            own.or_else(|| group.and_then(|group| of_group(group).clone()))
+                .map(|s| s.0)
+        };
+        Subject {
+            priority: self
+                .priority
+                .or(group.and_then(|group| group.priority))
+                .unwrap_or(1),
+            optional: self
+                .optional
+                .or(group.and_then(|group| group.optional))
+                .unwrap_or(false),
+            earliest: inherit(self.earliest, |group| &group.earliest),
+            latest: inherit(self.latest, |group| &group.latest),
+            pinned: self.pinned,
+            excluded: self.excluded,
+            ..Subject::new(leak(self.institute), leak(self.code))
+        }
+    }
+}
+
+/// Subjects live as long as the program, so their codes are leaked to be shared as
+/// `&'static str` by every timesheet and solution.
+fn leak(s: String) -> &'static str {
+    Box::leak(s.into_boxed_str())
+}
+
+/// Reads an `INSTITUTE:CODE[:TURMAS]` line.
+fn parse_line(line: &str) -> Result<SubjectOptions, String> {
+    let fields = line.split(':').map(str::trim).collect::<Vec<_>>();
+    let (institute, code, turmas) = match fields[..] {
+        [institute, code] => (institute, code, ""),
+        [institute, code, turmas] => (institute, code, turmas),
+        _ => {
+            return Err(format!(
+                "expected INSTITUTE:CODE[:TURMAS], found {:?}",
+                line
+            ))
+        }
+    };
+    if institute.is_empty() || code.is_empty() {
+        return Err(format!(
+            "expected INSTITUTE:CODE[:TURMAS], found {:?}",
+            line
+        ));
+    }
+    let (mut pinned, mut excluded) = (vec![], vec![]);
+    for turma in turmas.split(',').map(str::trim).filter(|t| !t.is_empty()) {
+        match turma.strip_prefix('!').map(str::trim) {
+            Some("") => return Err(format!("missing turma after '!' in {:?}", turmas)),
+            Some(turma) => excluded.push(turma.to_string()),
+            None => pinned.push(turma.to_string()),
+        }
+    }
+    Ok(SubjectOptions {
+        institute: institute.to_string(),
+        code: code.to_string(),
+        pinned,
+        excluded,
+        priority: None,
+        optional: None,
+        earliest: None,
+        latest: None,
+    })
+}
+
+/// A subject given either as an `INSTITUTE:CODE[:TURMAS]` string or as a map of its options.
+#[derive(Clone)]
+struct SubjectRepr(SubjectOptions);
+
+impl<'de> Deserialize<'de> for SubjectRepr {
+    fn deserialize<D: Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
+        struct SubjectVisitor;
+
+        impl<'de> Visitor<'de> for SubjectVisitor {
+            type Value = SubjectRepr;
+
+            fn expecting(&self, f: &mut fmt::Formatter) -> fmt::Result {
+                f.write_str("\"INSTITUTE:CODE[:TURMAS]\" or a map with institute and code")
+            }
+
+            fn visit_str<E: de::Error>(self, s: &str) -> Result<SubjectRepr, E> {
+                parse_line(s).map(SubjectRepr).map_err(E::custom)
+            }
+
+            fn visit_map<A: MapAccess<'de>>(self, map: A) -> Result<SubjectRepr, A::Error> {
+                SubjectOptions::deserialize(MapAccessDeserializer::new(map)).map(SubjectRepr)
+            }
+        }
+
+        deserializer.deserialize_any(SubjectVisitor)
+    }
+}
+
+/// A semester written as e.g. "1s2024".
+#[derive(Clone, PartialEq, Eq, PartialOrd, Ord)]
+struct SemesterRepr(Semester);
+
+impl<'de> Deserialize<'de> for SemesterRepr {
+    fn deserialize<D: Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
+        let s = String::deserialize(deserializer)?;
+        parse_semester(&s)
+            .map(SemesterRepr)
+            .map_err(de::Error::custom)
+    }
+}
+
+/// A time window written as in `--block`.
+struct WindowRepr(Vec<Slot>);
+
+impl<'de> Deserialize<'de> for WindowRepr {
+    fn deserialize<D: Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
+        let s = String::deserialize(deserializer)?;
+        parse_window(&s)
+            .map(|window| WindowRepr(window.0))
+            .map_err(de::Error::custom)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn path() -> PathBuf {
+        PathBuf::from("plan")
+    }
+
+    #[test]
+    fn reads_text_lines() {
+        let plan = parse_text("# Core\nIC:MC102\n\nIC:MC202: A, !B ,C\r\n", &path()).unwrap();
+        assert_eq!(plan.subjects.len(), 2);
+        assert_eq!(plan.subjects[1].code, "MC202");
+        assert_eq!(plan.subjects[1].pinned, ["A", "C"]);
+        assert_eq!(plan.subjects[1].excluded, ["B"]);
+    }
+
+    #[test]
+    fn reports_malformed_lines() {
+        let error = |text| parse_text(text, &path()).unwrap_err();
+        assert_eq!(error("IC:MC102\nMC202\n").line, Some(2));
+        assert_eq!(error("IC:MC102:A:B").line, Some(1));
+        assert_eq!(error("\n\nIC:MC102:!").line, Some(3));
+        assert_eq!(
+            error("IC:MC102\n# again\nIC:MC102").to_string(),
+            "plan:3: subject MC102 is listed twice"
+        );
+    }
+
+    #[test]
+    fn reads_yaml_plan() {
+        let path = Path::new(env!("CARGO_MANIFEST_DIR")).join("tests/fixtures/plan.yaml");
+        let plan = load(&path).unwrap();
+        assert_eq!(plan.semester, Some(Semester::from("1s2024")));
+        assert_eq!(plan.cr_max, Some(24));
+        assert_eq!(plan.cr_caps, [(Semester::from("2s2024"), 12)]);
+        assert_eq!(plan.blocked.len(), 1);
+        assert_eq!(plan.weights, [("free-days".to_string(), 2.0)]);
+        let codes = plan.subjects.iter().map(|s| s.code).collect::<Vec<_>>();
+        assert_eq!(codes, ["MC102", "MC202", "MC855", "MC859"]);
+        assert_eq!(plan.subjects[0].excluded, ["B"]);
+        assert_eq!(plan.subjects[1].priority, 2);
+        assert_eq!(plan.subjects[1].earliest, Some(Semester::from("2s2024")));
+        // Group options apply unless the subject gives its own
+        assert_eq!(plan.subjects[2].latest, Some(Semester::from("1s2026")));
+        assert_eq!(plan.subjects[2].priority, 1);
+        assert!(plan.subjects[2].optional && !plan.subjects[1].optional);
+        assert_eq!(plan.subjects[3].latest, Some(Semester::from("2s2025")));
+        assert_eq!(plan.electives.len(), 1);
+        assert_eq!(plan.electives[0].subjects, ["MC855", "MC859"]);
+        assert_eq!(plan.electives[0].min_count, 1);
+    }
+
+    #[test]
+    fn reports_yaml_errors_with_line() {
+        let error = |text| parse_yaml(text, &path()).unwrap_err();
+        let e = error("semester: 1s2024\nsubjects:\n  - IC:MC102\n  - MC202\n");
+        assert_eq!(e.line, Some(4));
+        assert!(e.reason.contains("INSTITUTE:CODE"), "{}", e.reason);
+        assert_eq!(error("semester: 3s2024\n").line, Some(1));
+        assert_eq!(error("cr_max: 24\ncrmin: 4\n").line, Some(2));
+        assert_eq!(
+            error("subjects: [IC:MC102]\ngroups:\n  - {name: Core, subjects: [IC:MC102]}\n")
+                .to_string(),
+            "plan: subject MC102 is listed twice, in subjects and Core"
+        );
+        assert_eq!(
+            error("groups:\n  - {name: Core, min_count: 2, subjects: [IC:MC102]}\n").to_string(),
+            "plan: group Core asks for 2 subjects but has only 1"
+        );
+    }
+}
@@ -0,0 +1,317 @@
+use itertools::Itertools;
+use std::collections::HashMap;
+use std::fmt::Write;
+
+use crate::scoring::Scoring;
+use crate::solving::Solution;
+use crate::unicamp::{Class, Semester, Slot, WEEKDAYS};
+
+const HOUR_HEIGHT: u32 = 48;
+const DAY_WIDTH: u32 = 128;
+const LABELS_WIDTH: u32 = 56;
+const HEADER_HEIGHT: u32 = 28;
+
+/// Hours always shown in the grid, so that semesters of a solution line up unless a class
+/// falls outside of them.
+const FIRST_HOUR: u16 = 8;
+const LAST_HOUR: u16 = 18;
+
+/// Builds a self-contained HTML page showing the `rank`-th solution, with an SVG weekly grid
+/// per semester and links to each of them at the top.
+pub fn to_html(
+    rank: usize,
+    solution: &Solution,
+    semesters: &[Semester],
+    scoring: &Scoring,
+) -> String {
+    let colors = subject_colors(
+        solution
+            .schedules
+            .iter()
+            .flat_map(|schedule| schedule.table.keys().copied()),
+    );
+    let mut html = String::new();
+    writeln!(html, "<!DOCTYPE html>").unwrap();
+    writeln!(
+        html,
+        "<html lang=\"pt-BR\">\n<head>\n<meta charset=\"utf-8\">"
+    )
+    .unwrap();
+    writeln!(html, "<title>Solution {}</title>", rank).unwrap();
+    writeln!(
+        html,
+        "<style>body {{ font-family: sans-serif; margin: 2em; }} \
+         nav a {{ margin-right: 1em; }} svg {{ display: block; margin: 1em 0; }}</style>"
+    )
+    .unwrap();
+    writeln!(html, "</head>\n<body>").unwrap();
+    writeln!(html, "<h1>Solution {}</h1>", rank).unwrap();
+    writeln!(
+        html,
+        "<p>Score: {} ({})</p>",
+        solution.score,
+        solution
+            .breakdown
+            .iter()
+            .map(|(name, value)| format!("{}: {} x {}", name, value, scoring.weight(name)))
+            .join(", ")
+    )
+    .unwrap();
+    for (group, subjects) in solution.electives.iter() {
+        writeln!(
+            html,
+            "<p>{}: {}</p>",
+            escape(group),
+            subjects.iter().map(|subject| escape(subject)).join(", ")
+        )
+        .unwrap();
+    }
+    for (subject, reason) in solution.missing.iter() {
+        writeln!(
+            html,
+            "<p>Left out {}: {}</p>",
+            escape(subject),
+            escape(reason)
+        )
+        .unwrap();
+    }
+    writeln!(html, "<p>{}.</p>", solution.search).unwrap();
+    if let Some(stop) = solution.search.stopped {
+        writeln!(
+            html,
+            "<p>Stopped at {}, better solutions may exist.</p>",
+            stop
+        )
+        .unwrap();
+    }
+    let sections = solution.schedules.iter().zip(semesters).collect::<Vec<_>>();
+    writeln!(
+        html,
+        "<nav>{}</nav>",
+        sections
+            .iter()
+            .map(|(_, semester)| format!("<a href=\"#{0}\">{0}</a>", semester))
+            .join("")
+    )
+    .unwrap();
+    for (schedule, semester) in sections {
+        writeln!(
+            html,
+            "<section id=\"{}\">\n<h2>{} ({} credits, score {})</h2>",
+            semester, semester, schedule.cr_count, schedule.score
+        )
+        .unwrap();
+        html.push_str(&to_svg(&schedule.table, &colors));
+        writeln!(html, "</section>").unwrap();
+    }
+    writeln!(html, "</body>\n</html>").unwrap();
+    html
+}
+
+/// Draws the weekly grid of a semester, placing classes at their exact minutes. Saturday and
+/// Sunday are only shown when they have classes.
+pub fn to_svg(table: &HashMap<&str, Class>, colors: &HashMap<&str, String>) -> String {
+    let slots = table
+        .iter()
+        .sorted_by_key(|(code, _)| **code)
+        .flat_map(|(code, class)| class.slots.iter().map(move |slot| (*code, class, slot)))
+        .collect::<Vec<_>>();
+    let weekdays = (1..=7)
+        .filter(|d| (2..=6).contains(d) || slots.iter().any(|(_, _, slot)| slot.weekday == *d))
+        .collect::<Vec<u8>>();
+    let first_hour = slots
+        .iter()
+        .map(|(_, _, slot)| slot.start / 100)
+        .min()
+        .map_or(FIRST_HOUR, |hour| hour.min(FIRST_HOUR));
+    let last_hour = slots
+        .iter()
+        .map(|(_, _, slot)| minutes(slot.finish).div_ceil(60))
+        .max()
+        .map_or(LAST_HOUR, |hour| hour.max(LAST_HOUR));
+    let y = |time: u16| HEADER_HEIGHT + (minutes(time) - first_hour * 60) as u32 * HOUR_HEIGHT / 60;
+    let width = LABELS_WIDTH + DAY_WIDTH * weekdays.len() as u32;
+    let height = HEADER_HEIGHT + (last_hour - first_hour) as u32 * HOUR_HEIGHT;
+
+    let mut svg = String::new();
+    writeln!(
+        svg,
+        "<svg xmlns=\"http://www.w3.org/2000/svg\" width=\"{0}\" height=\"{1}\" \
+         viewBox=\"0 0 {0} {1}\" font-family=\"sans-serif\" font-size=\"11\">",
+        width, height
+    )
+    .unwrap();
+    for hour in first_hour..=last_hour {
+        let line_y = y(hour * 100);
+        writeln!(
+            svg,
+            "<line x1=\"{}\" y1=\"{2}\" x2=\"{}\" y2=\"{2}\" stroke=\"#ddd\"/>",
+            LABELS_WIDTH, width, line_y
+        )
+        .unwrap();
+        if hour < last_hour {
+            writeln!(
+                svg,
+                "<text x=\"{}\" y=\"{}\" text-anchor=\"end\" fill=\"#666\">{:0>2}:00</text>",
+                LABELS_WIDTH - 6,
+                line_y + 4,
+                hour
+            )
+            .unwrap();
+        }
+    }
+    for (i, weekday) in weekdays.iter().enumerate() {
+        let x = LABELS_WIDTH + DAY_WIDTH * i as u32;
+        writeln!(
+            svg,
+            "<line x1=\"{0}\" y1=\"0\" x2=\"{0}\" y2=\"{1}\" stroke=\"#ddd\"/>",
+            x, height
+        )
+        .unwrap();
+        writeln!(
+            svg,
+            "<text x=\"{}\" y=\"{}\" text-anchor=\"middle\" font-weight=\"bold\">{}</text>",
+            x + DAY_WIDTH / 2,
+            HEADER_HEIGHT - 10,
+            WEEKDAYS[(weekday - 1) as usize]
+        )
+        .unwrap();
+    }
+    for (code, class, slot) in slots {
+        let column = weekdays.iter().position(|d| *d == slot.weekday).unwrap() as u32;
+        let (x, top) = (LABELS_WIDTH + DAY_WIDTH * column + 2, y(slot.start));
+        let title = if class.code.is_empty() {
+            code.to_string()
+        } else {
+            format!("{} {}", code, class.code)
+        };
+        let mut lines = vec![title, time_range(slot)];
+        lines.extend(slot.room.clone());
+        writeln!(svg, "<g>").unwrap();
+        writeln!(
+            svg,
+            "<title>{}</title>",
+            escape(&format!("{}: {}", code, class))
+        )
+        .unwrap();
+        writeln!(
+            svg,
+            "<rect x=\"{}\" y=\"{}\" width=\"{}\" height=\"{}\" rx=\"4\" fill=\"{}\"/>",
+            x,
+            top,
+            DAY_WIDTH - 4,
+            y(slot.finish) - top,
+            colors.get(code).map_or("#ccc", |color| color.as_str())
+        )
+        .unwrap();
+        for (i, line) in lines.iter().enumerate() {
+            writeln!(
+                svg,
+                "<text x=\"{}\" y=\"{}\"{}>{}</text>",
+                x + 6,
+                top + 14 + 13 * i as u32,
+                if i == 0 { " font-weight=\"bold\"" } else { "" },
+                escape(line)
+            )
+            .unwrap();
+        }
+        writeln!(svg, "</g>").unwrap();
+    }
+    writeln!(svg, "</svg>").unwrap();
+    svg
+}
+
+/// Assigns each subject a light color, spreading hues by the golden angle in sorted order so
+/// the same subject keeps its color in every semester.
+pub fn subject_colors<'a>(subjects: impl Iterator<Item = &'a str>) -> HashMap<&'a str, String> {
+    subjects
+        .sorted()
+        .dedup()
+        .enumerate()
+        .map(|(i, code)| {
+            (
+                code,
+                format!("hsl({:.0}, 70%, 80%)", (i as f32 * 137.508) % 360f32),
+            )
+        })
+        .collect()
+}
+
+/// Minutes since midnight of a HHMM time.
+fn minutes(time: u16) -> u16 {
+    (time / 100) * 60 + time % 100
+}
+
+fn time_range(slot: &Slot) -> String {
+    format!(
+        "{:0>2}:{:0>2}-{:0>2}:{:0>2}",
+        slot.start / 100,
+        slot.start % 100,
+        slot.finish / 100,
+        slot.finish % 100
+    )
+}
+
+fn escape(text: &str) -> String {
+    text.replace('&', "&amp;")
+        .replace('<', "&lt;")
+        .replace('>', "&gt;")
+        .replace('"', "&quot;")
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn classes_are_placed_at_their_minutes() {
+        let table = HashMap::from([(
+            "MC102",
+            Class {
+                code: "A".to_string(),
+                slots: vec![
+                    Slot {
+                        room: Some("CB01".to_string()),
+                        ..Slot::parse("Segunda", "07:30 - 09:00").unwrap()
+                    },
+                    Slot::parse("Sábado", "19:00 - 23:30").unwrap(),
+                ],
+                ..Default::default()
+            },
+        )]);
+        let svg = to_svg(&table, &subject_colors(["MC102"].into_iter()));
+        // The grid spans 07:00 to 24:00 and Saturday is shown, Sunday is not
+        assert!(svg.contains(&format!("height=\"{}\"", HEADER_HEIGHT + 17 * HOUR_HEIGHT)));
+        assert!(svg.contains(">Sábado<") && !svg.contains(">Domingo<"));
+        assert!(svg.contains(&format!(
+            "<rect x=\"{}\" y=\"{}\" width=\"{}\" height=\"{}\" rx=\"4\" fill=\"hsl(0, 70%, 80%)\"/>",
+            LABELS_WIDTH + 2,
+            HEADER_HEIGHT + HOUR_HEIGHT / 2,
+            DAY_WIDTH - 4,
+            HOUR_HEIGHT * 3 / 2
+        )));
+        assert!(svg.contains(">07:30-09:00<") && svg.contains(">CB01<"));
+    }
+
+    #[test]
+    fn subject_codes_are_escaped() {
+        let solution = Solution {
+            schedules: vec![],
+            score: 0f32,
+            breakdown: vec![],
+            electives: vec![("Electives".to_string(), vec!["<b>MC855"])],
+            missing: vec![("MC&999", "not offered".to_string())],
+            search: Default::default(),
+        };
+        let html = to_html(1, &solution, &[], &Scoring::default());
+        assert!(html.contains("<p>Electives: &lt;b&gt;MC855</p>"));
+        assert!(html.contains("<p>Left out MC&amp;999: not offered</p>"));
+    }
+
+    #[test]
+    fn colors_are_distinct_per_subject() {
+        let colors = subject_colors(["MC202", "MC102", "MC202", "MA111"].into_iter());
+        assert_eq!(colors.len(), 3);
+        assert_eq!(colors.values().unique().count(), 3);
+    }
+}
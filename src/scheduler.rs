@@ -0,0 +1,252 @@
+use std::sync::atomic::{AtomicBool, AtomicUsize, Ordering};
+use std::sync::{mpsc, Mutex};
+use std::thread;
+use std::time::{Duration, Instant};
+
+use crate::fetching::{FetchError, Fetcher};
+use crate::unicamp::{Semester, Subject};
+
+/// Fetches pages with a bounded pool of workers, at most `rate` requests per second overall,
+/// retrying transient failures with exponential backoff.
+pub struct Scheduler {
+    pub fetcher: Box<dyn Fetcher>,
+    pub workers: usize,
+    pub rate: u32,
+    pub retries: u32,
+    /// Wait before the first retry, doubled before each of the next ones.
+    pub backoff: Duration,
+}
+
+impl Scheduler {
+    pub fn new(fetcher: Box<dyn Fetcher>) -> Self {
+        Scheduler {
+            fetcher,
+            workers: 4,
+            rate: 2,
+            retries: 3,
+            backoff: Duration::from_millis(500),
+        }
+    }
+
+    pub fn source(&self, subject: &Subject, semester: &Semester) -> String {
+        self.fetcher.source(subject, semester)
+    }
+
+    /// Fetches the pages of `subjects` in `semester`, calling `on_page` on this thread with
+    /// each one as it arrives. Stops handing out pages once `on_page` fails, returning its
+    /// error after the pages being fetched arrive.
+    pub fn fetch_all<'s, E>(
+        &self,
+        subjects: &[&'s Subject],
+        semester: &Semester,
+        mut on_page: impl FnMut(&'s Subject, Result<Option<String>, String>) -> Result<(), E>,
+    ) -> Result<(), E> {
+        let limiter = RateLimiter::new(self.rate);
+        let next = AtomicUsize::new(0);
+        let cancelled = AtomicBool::new(false);
+        let (sender, receiver) = mpsc::channel();
+        thread::scope(|scope| {
+            for _ in 0..self.workers.clamp(1, subjects.len().max(1)) {
+                let sender = sender.clone();
+                let (limiter, next, cancelled) = (&limiter, &next, &cancelled);
+                scope.spawn(move || {
+                    while !cancelled.load(Ordering::Relaxed) {
+                        let Some(subject) = subjects.get(next.fetch_add(1, Ordering::Relaxed))
+                        else {
+                            break;
+                        };
+                        let page = self.fetch(limiter, subject, semester);
+                        if sender.send((*subject, page)).is_err() {
+                            break;
+                        }
+                    }
+                });
+            }
+            drop(sender);
+            let mut result = Ok(());
+            for (done, (subject, page)) in receiver.iter().enumerate() {
+                if result.is_err() {
+                    continue;
+                }
+                println!(
+                    "[{}/{}] {} subject {} in semester {}.",
+                    done + 1,
+                    subjects.len(),
+                    if page.is_ok() {
+                        "Fetched"
+                    } else {
+                        "Failed to fetch"
+                    },
+                    subject.code,
+                    semester
+                );
+                result = on_page(subject, page);
+                if result.is_err() {
+                    cancelled.store(true, Ordering::Relaxed);
+                }
+            }
+            result
+        })
+    }
+
+    fn fetch(
+        &self,
+        limiter: &RateLimiter,
+        subject: &Subject,
+        semester: &Semester,
+    ) -> Result<Option<String>, String> {
+        let mut attempt = 0;
+        loop {
+            limiter.wait();
+            match self.fetcher.fetch(subject, semester) {
+                Err(FetchError {
+                    reason,
+                    transient: true,
+                }) if attempt < self.retries => {
+                    let delay = self.backoff * 2u32.pow(attempt);
+                    println!(
+                        "Could not fetch subject {} in semester {} ({}), retrying in {:.1}s...",
+                        subject.code,
+                        semester,
+                        reason,
+                        delay.as_secs_f32()
+                    );
+                    thread::sleep(delay);
+                    attempt += 1;
+                }
+                result => return result.map_err(|e| e.reason),
+            }
+        }
+    }
+}
+
+/// Spaces requests evenly, shared by every worker.
+struct RateLimiter {
+    interval: Duration,
+    next: Mutex<Instant>,
+}
+
+impl RateLimiter {
+    fn new(rate: u32) -> Self {
+        RateLimiter {
+            interval: Duration::from_secs(1) / rate.max(1),
+            next: Mutex::new(Instant::now()),
+        }
+    }
+
+    /// Blocks until the next request may be made.
+    fn wait(&self) {
+        let slot = {
+            let mut next = self.next.lock().unwrap();
+            let slot = (*next).max(Instant::now());
+            *next = slot + self.interval;
+            slot
+        };
+        thread::sleep(slot.saturating_duration_since(Instant::now()));
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::collections::HashMap;
+
+    /// Fails the first `failures` fetches of each subject, transiently unless the subject is
+    /// MC999.
+    struct FlakyFetcher {
+        failures: u32,
+        calls: Mutex<HashMap<&'static str, u32>>,
+    }
+
+    impl Fetcher for FlakyFetcher {
+        fn fetch(&self, subject: &Subject, _: &Semester) -> Result<Option<String>, FetchError> {
+            let mut calls = self.calls.lock().unwrap();
+            let count = calls.entry(subject.code).or_default();
+            *count += 1;
+            if *count <= self.failures {
+                return Err(FetchError {
+                    reason: "HTTP 503".to_string(),
+                    transient: subject.code != "MC999",
+                });
+            }
+            Ok(Some(subject.code.to_string()))
+        }
+
+        fn source(&self, _: &Subject, _: &Semester) -> String {
+            String::new()
+        }
+    }
+
+    fn subject(code: &'static str) -> Subject {
+        Subject::new("IC", code)
+    }
+
+    fn scheduler(failures: u32) -> Scheduler {
+        Scheduler {
+            workers: 3,
+            rate: 1000,
+            retries: 2,
+            backoff: Duration::from_millis(1),
+            ..Scheduler::new(Box::new(FlakyFetcher {
+                failures,
+                calls: Mutex::new(HashMap::new()),
+            }))
+        }
+    }
+
+    #[test]
+    fn retries_transient_failures() {
+        let subjects = ["MC102", "MC202", "MC322", "MC999"].map(subject);
+        let subjects = subjects.iter().collect::<Vec<_>>();
+        let mut pages = HashMap::new();
+        scheduler(2)
+            .fetch_all(&subjects, &Semester::from("1s2024"), |subject, page| {
+                pages.insert(subject.code, page);
+                Ok::<_, ()>(())
+            })
+            .unwrap();
+        assert_eq!(pages.len(), 4);
+        assert_eq!(pages["MC102"], Ok(Some("MC102".to_string())));
+        assert_eq!(pages["MC999"], Err("HTTP 503".to_string()));
+
+        let mut pages = HashMap::new();
+        scheduler(3)
+            .fetch_all(
+                &subjects[..1],
+                &Semester::from("1s2024"),
+                |subject, page| {
+                    pages.insert(subject.code, page);
+                    Ok::<_, ()>(())
+                },
+            )
+            .unwrap();
+        assert_eq!(pages["MC102"], Err("HTTP 503".to_string()));
+    }
+
+    #[test]
+    fn stops_on_error() {
+        let subjects = (0..20).map(|_| subject("MC102")).collect::<Vec<_>>();
+        let subjects = subjects.iter().collect::<Vec<_>>();
+        let mut seen = 0;
+        let result = Scheduler {
+            workers: 1,
+            ..scheduler(0)
+        }
+        .fetch_all(&subjects, &Semester::from("1s2024"), |_, _| {
+            seen += 1;
+            Err("stop")
+        });
+        assert_eq!(result, Err("stop"));
+        assert_eq!(seen, 1);
+    }
+
+    #[test]
+    fn limits_rate() {
+        let limiter = RateLimiter::new(50);
+        let start = Instant::now();
+        for _ in 0..6 {
+            limiter.wait();
+        }
+        assert!(start.elapsed() >= Duration::from_millis(100));
+    }
+}
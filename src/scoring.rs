@@ -0,0 +1,445 @@
+use itertools::Itertools;
+
+use crate::unicamp::Class;
+use std::collections::{BTreeMap, HashMap};
+
+/// A criterion solutions are ranked by. Higher values are better.
+pub trait Scorer: Sync {
+    fn name(&self) -> &'static str;
+
+    /// Scores a single semester.
+    fn score_semester(&self, _table: &HashMap<&str, Class>) -> f32 {
+        0f32
+    }
+
+    /// Scores a whole solution, by default as the mean of its semesters.
+    fn score_solution(&self, tables: &[&HashMap<&str, Class>]) -> f32 {
+        if tables.is_empty() {
+            return 0f32;
+        }
+        tables.iter().map(|t| self.score_semester(t)).sum::<f32>() / tables.len() as f32
+    }
+
+    /// Least and greatest value of a semester holding at least the classes in `partial`,
+    /// unbounded unless known.
+    fn semester_range(&self, _partial: &HashMap<&str, Class>) -> (f32, f32) {
+        (f32::NEG_INFINITY, f32::INFINITY)
+    }
+
+    /// Least and greatest value of a solution whose first semesters are `done`, followed by one
+    /// holding at least the classes in `current` and, if `more`, maybe others. By default the
+    /// mean of the semesters, which lies between that of `done` and `current` and the range of
+    /// the others.
+    fn solution_range(
+        &self,
+        done: &[&HashMap<&str, Class>],
+        current: &HashMap<&str, Class>,
+        more: bool,
+    ) -> (f32, f32) {
+        let sum = done.iter().map(|t| self.score_semester(t)).sum::<f32>();
+        let count = done.len() as f32 + 1f32;
+        let (cmin, cmax) = self.semester_range(current);
+        let (mut min, mut max) = ((sum + cmin) / count, (sum + cmax) / count);
+        if more {
+            let (fmin, fmax) = self.semester_range(&HashMap::new());
+            min = min.min(fmin);
+            max = max.max(fmax);
+        }
+        (min, max)
+    }
+}
+
+/// Hours of class divided by the squared mean distance to their centroid in the week grid. No
+/// useful bound on it is known, so weighing it keeps the search from pruning on score.
+pub struct Compactness;
+
+impl Scorer for Compactness {
+    fn name(&self) -> &'static str {
+        "compactness"
+    }
+
+    fn score_semester(&self, table: &HashMap<&str, Class>) -> f32 {
+        let mut points = vec![];
+        // In a fixed order, so that sums round the same way every time
+        for (_, cl) in table.iter().sorted_by_key(|(code, _)| **code) {
+            for sl in cl.slots.iter() {
+                for i in (sl.start / 100)..(sl.finish / 100) {
+                    points.push((sl.weekday as f32, i as f32));
+                }
+            }
+        }
+        let centroid = points.iter().fold((0f32, 0f32), |sum, (x, y)| {
+            (
+                sum.0 + x / points.len() as f32,
+                sum.1 + y / points.len() as f32,
+            )
+        });
+        let score = (points.len() as f32)
+            / points
+                .iter()
+                .fold(0f32, |sum, (x, y)| {
+                    sum + ((centroid.0 - x).powf(2f32) + (centroid.1 - y).powf(2f32)).sqrt()
+                        / points.len() as f32
+                })
+                .powf(2f32);
+        if score.is_finite() {
+            return score;
+        }
+        0f32
+    }
+
+    fn semester_range(&self, _partial: &HashMap<&str, Class>) -> (f32, f32) {
+        (0f32, f32::INFINITY)
+    }
+}
+
+/// Number of weekdays (Monday to Friday) without classes.
+pub struct FreeDays;
+
+impl Scorer for FreeDays {
+    fn name(&self) -> &'static str {
+        "free-days"
+    }
+
+    fn score_semester(&self, table: &HashMap<&str, Class>) -> f32 {
+        let days = class_days(table);
+        (2..=6).filter(|d| !days.contains_key(d)).count() as f32
+    }
+
+    /// Classes only take days away.
+    fn semester_range(&self, partial: &HashMap<&str, Class>) -> (f32, f32) {
+        (0f32, self.score_semester(partial))
+    }
+}
+
+/// Minus the hours spent waiting between classes of the same day.
+pub struct IdleGaps;
+
+impl Scorer for IdleGaps {
+    fn name(&self) -> &'static str {
+        "idle-gaps"
+    }
+
+    fn score_semester(&self, table: &HashMap<&str, Class>) -> f32 {
+        let idle = class_days(table)
+            .values()
+            .flat_map(|intervals| intervals.windows(2).map(|w| w[1].0.saturating_sub(w[0].1)))
+            .sum::<u16>();
+        0f32 - idle as f32 / 60f32
+    }
+
+    fn semester_range(&self, _partial: &HashMap<&str, Class>) -> (f32, f32) {
+        (f32::NEG_INFINITY, 0f32)
+    }
+}
+
+/// Mean hour the first class of each class day starts at.
+pub struct EarliestStart;
+
+impl Scorer for EarliestStart {
+    fn name(&self) -> &'static str {
+        "earliest-start"
+    }
+
+    fn score_semester(&self, table: &HashMap<&str, Class>) -> f32 {
+        let days = class_days(table);
+        if days.is_empty() {
+            return 0f32;
+        }
+        days.values().map(|i| i[0].0 as f32 / 60f32).sum::<f32>() / days.len() as f32
+    }
+
+    fn semester_range(&self, _partial: &HashMap<&str, Class>) -> (f32, f32) {
+        (0f32, 24f32)
+    }
+}
+
+/// Minus the mean hour the last class of each class day finishes at.
+pub struct LatestFinish;
+
+impl Scorer for LatestFinish {
+    fn name(&self) -> &'static str {
+        "latest-finish"
+    }
+
+    fn score_semester(&self, table: &HashMap<&str, Class>) -> f32 {
+        let days = class_days(table);
+        if days.is_empty() {
+            return 0f32;
+        }
+        -days
+            .values()
+            .map(|i| i.iter().map(|x| x.1).max().unwrap() as f32 / 60f32)
+            .sum::<f32>()
+            / days.len() as f32
+    }
+
+    fn semester_range(&self, _partial: &HashMap<&str, Class>) -> (f32, f32) {
+        (-24f32, 0f32)
+    }
+}
+
+/// Fraction of class days with a free hour between 11:00 and 14:00.
+pub struct LunchBreak;
+
+impl LunchBreak {
+    const FROM: u16 = 11 * 60;
+    const TO: u16 = 14 * 60;
+    const LENGTH: u16 = 60;
+}
+
+impl Scorer for LunchBreak {
+    fn name(&self) -> &'static str {
+        "lunch-break"
+    }
+
+    fn score_semester(&self, table: &HashMap<&str, Class>) -> f32 {
+        let days = class_days(table);
+        if days.is_empty() {
+            return 1f32;
+        }
+        let with_lunch = days
+            .values()
+            .filter(|intervals| {
+                let mut free_from = Self::FROM;
+                for (start, finish) in intervals.iter() {
+                    if start.min(&Self::TO).saturating_sub(free_from) >= Self::LENGTH {
+                        return true;
+                    }
+                    free_from = free_from.max(*finish);
+                }
+                Self::TO.saturating_sub(free_from) >= Self::LENGTH
+            })
+            .count();
+        with_lunch as f32 / days.len() as f32
+    }
+
+    fn semester_range(&self, _partial: &HashMap<&str, Class>) -> (f32, f32) {
+        (0f32, 1f32)
+    }
+}
+
+/// Minus the number of semesters the solution takes.
+pub struct Semesters;
+
+impl Scorer for Semesters {
+    fn name(&self) -> &'static str {
+        "semesters"
+    }
+
+    fn score_solution(&self, tables: &[&HashMap<&str, Class>]) -> f32 {
+        -(tables.len() as f32)
+    }
+
+    fn solution_range(
+        &self,
+        done: &[&HashMap<&str, Class>],
+        _current: &HashMap<&str, Class>,
+        more: bool,
+    ) -> (f32, f32) {
+        let count = -(done.len() as f32 + 1f32);
+        (if more { f32::NEG_INFINITY } else { count }, count)
+    }
+}
+
+/// Sorted (start, finish) minutes of the classes of each weekday.
+fn class_days(table: &HashMap<&str, Class>) -> BTreeMap<u8, Vec<(u16, u16)>> {
+    let mut days: BTreeMap<u8, Vec<(u16, u16)>> = BTreeMap::new();
+    for slot in table.values().flat_map(|class| class.slots.iter()) {
+        days.entry(slot.weekday).or_default().push((
+            (slot.start / 100) * 60 + slot.start % 100,
+            (slot.finish / 100) * 60 + slot.finish % 100,
+        ));
+    }
+    for intervals in days.values_mut() {
+        intervals.sort();
+    }
+    days
+}
+
+pub fn scorers() -> Vec<Box<dyn Scorer>> {
+    vec![
+        Box::new(Compactness),
+        Box::new(FreeDays),
+        Box::new(IdleGaps),
+        Box::new(EarliestStart),
+        Box::new(LatestFinish),
+        Box::new(LunchBreak),
+        Box::new(Semesters),
+    ]
+}
+
+/// Weighted sum of scorers. Only compactness counts by default.
+pub struct Scoring {
+    criteria: Vec<(Box<dyn Scorer>, f32)>,
+}
+
+impl Default for Scoring {
+    fn default() -> Self {
+        Scoring {
+            criteria: vec![(Box::new(Compactness), 1f32)],
+        }
+    }
+}
+
+impl Scoring {
+    /// Overrides the default weights with `weights`, keyed by scorer name.
+    pub fn new(weights: &[(String, f32)]) -> Result<Self, String> {
+        let mut scoring = Scoring::default();
+        for (name, weight) in weights {
+            scoring.criteria.retain(|(scorer, _)| scorer.name() != name);
+            let scorer = scorers()
+                .into_iter()
+                .find(|scorer| scorer.name() == name)
+                .ok_or(format!(
+                    "unknown scoring criterion {:?}, expected one of: {}",
+                    name,
+                    scorers()
+                        .iter()
+                        .map(|s| s.name())
+                        .collect::<Vec<_>>()
+                        .join(", ")
+                ))?;
+            scoring.criteria.push((scorer, *weight));
+        }
+        scoring.criteria.retain(|(_, weight)| *weight != 0f32);
+        Ok(scoring)
+    }
+
+    /// Weighted score of a single semester, ignoring solution-wide criteria.
+    pub fn score_semester(&self, table: &HashMap<&str, Class>) -> f32 {
+        self.criteria
+            .iter()
+            .map(|(scorer, weight)| weight * scorer.score_semester(table))
+            .sum()
+    }
+
+    /// Weighted score of a solution and the unweighted value of each criterion.
+    pub fn score_solution(
+        &self,
+        tables: &[&HashMap<&str, Class>],
+    ) -> (f32, Vec<(&'static str, f32)>) {
+        let breakdown = self
+            .criteria
+            .iter()
+            .map(|(scorer, _)| (scorer.name(), scorer.score_solution(tables)))
+            .collect::<Vec<_>>();
+        let score = std::iter::zip(&self.criteria, &breakdown)
+            .map(|((_, weight), (_, value))| weight * value)
+            .sum();
+        (score, breakdown)
+    }
+
+    /// Greatest score a solution whose first semesters are `done`, followed by one holding at
+    /// least the classes in `current` and, if `more`, maybe others, may reach.
+    pub fn upper_bound(
+        &self,
+        done: &[&HashMap<&str, Class>],
+        current: &HashMap<&str, Class>,
+        more: bool,
+    ) -> f32 {
+        self.criteria
+            .iter()
+            .map(|(scorer, weight)| {
+                let (min, max) = scorer.solution_range(done, current, more);
+                if *weight > 0f32 {
+                    weight * max
+                } else {
+                    weight * min
+                }
+            })
+            .sum()
+    }
+
+    pub fn weight(&self, name: &str) -> f32 {
+        self.criteria
+            .iter()
+            .find(|(scorer, _)| scorer.name() == name)
+            .map_or(0f32, |(_, weight)| *weight)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::unicamp::Slot;
+
+    fn class(slots: &[(&str, &str)]) -> Class {
+        Class {
+            slots: slots
+                .iter()
+                .map(|(weekday, duration)| Slot::parse(weekday, duration).unwrap())
+                .collect(),
+            ..Default::default()
+        }
+    }
+
+    fn table() -> HashMap<&'static str, Class> {
+        HashMap::from([
+            (
+                "MC102",
+                class(&[("Segunda", "08:00 - 10:00"), ("Quarta", "08:00 - 10:00")]),
+            ),
+            ("MA111", class(&[("Segunda", "12:30 - 14:00")])),
+        ])
+    }
+
+    #[test]
+    fn day_based_criteria() {
+        let table = table();
+        assert_eq!(FreeDays.score_semester(&table), 3f32);
+        assert_eq!(IdleGaps.score_semester(&table), -2.5);
+        assert_eq!(EarliestStart.score_semester(&table), 8f32);
+        assert_eq!(LatestFinish.score_semester(&table), -12f32);
+        assert_eq!(LunchBreak.score_semester(&table), 1f32);
+        let crowded = HashMap::from([("MC102", class(&[("Segunda", "11:30 - 13:30")]))]);
+        assert_eq!(LunchBreak.score_semester(&crowded), 0f32);
+    }
+
+    #[test]
+    fn weights_override_defaults() {
+        let table = table();
+        let scoring = Scoring::new(&[
+            ("compactness".to_string(), 0f32),
+            ("free-days".to_string(), 2f32),
+            ("semesters".to_string(), 1f32),
+        ])
+        .unwrap();
+        assert_eq!(scoring.weight("compactness"), 0f32);
+        assert_eq!(scoring.score_semester(&table), 6f32);
+        let (score, breakdown) = scoring.score_solution(&[&table, &HashMap::new()]);
+        assert_eq!(breakdown, [("free-days", 4f32), ("semesters", -2f32)]);
+        assert_eq!(score, 6f32);
+        assert!(Scoring::new(&[("foo".to_string(), 1f32)]).is_err());
+    }
+
+    #[test]
+    fn bounds_solutions_in_progress() {
+        let table = table();
+        let scoring = Scoring::new(&[
+            ("compactness".to_string(), 0f32),
+            ("free-days".to_string(), 1f32),
+            ("earliest-start".to_string(), -1f32),
+            ("semesters".to_string(), 1f32),
+        ])
+        .unwrap();
+        let empty = HashMap::new();
+        // Semesters to come may have no classes at all
+        assert_eq!(scoring.upper_bound(&[], &empty, true), 5f32 - 0f32 - 1f32);
+        // Classes only take free days away, and the solution may end with the current semester
+        assert_eq!(scoring.upper_bound(&[], &table, false), 3f32 - 0f32 - 1f32);
+        // Free days add up to 8 over the semesters done, and earliest-start to 8
+        assert_eq!(
+            scoring.upper_bound(&[&table, &empty], &table, false),
+            (8f32 + 3f32) / 3f32 - 8f32 / 3f32 - 3f32
+        );
+        assert_eq!(
+            scoring.upper_bound(&[&table, &empty], &empty, true),
+            5f32 - 0f32 - 3f32
+        );
+        assert_eq!(
+            Scoring::default().upper_bound(&[&table], &empty, false),
+            f32::INFINITY
+        );
+    }
+}
@@ -1,107 +1,734 @@
-use crate::cache::{load_classes, load_credits, save_classes, save_credits};
-use crate::unicamp::{Class, Semester, Slot, Subject, Timesheet};
+use crate::cache::{CacheError, CacheMode, CachePolicy, ClassesEntry, Offering, Store};
+use crate::scheduler::Scheduler;
+use crate::unicamp::{Class, Exclusion, Prerequisites, Semester, Slot, Subject, Timesheet};
 use select::document::Document;
+use select::node::Node;
 use select::predicate;
 use std::collections::HashMap;
-use std::{thread, time};
+use std::fmt;
+use time::OffsetDateTime;
 
+#[derive(Debug, PartialEq)]
+pub enum ScrapeError {
+    /// An element expected in the page is not there.
+    MissingField {
+        subject: String,
+        field: &'static str,
+    },
+    /// An element is there but its text could not be parsed.
+    InvalidField {
+        subject: String,
+        field: &'static str,
+        value: String,
+    },
+    /// The cache could not be read or written.
+    Cache(CacheError),
+}
+
+impl From<CacheError> for ScrapeError {
+    fn from(e: CacheError) -> Self {
+        ScrapeError::Cache(e)
+    }
+}
+
+impl fmt::Display for ScrapeError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            ScrapeError::MissingField { subject, field } => {
+                write!(f, "Missing {} in page of subject {}", field, subject)
+            }
+            ScrapeError::InvalidField {
+                subject,
+                field,
+                value,
+            } => write!(
+                f,
+                "Invalid {} {:?} in page of subject {}",
+                field, value, subject
+            ),
+            ScrapeError::Cache(e) => e.fmt(f),
+        }
+    }
+}
+
+impl std::error::Error for ScrapeError {}
+
+/// Builds the timesheet of `subjects` in `semester`, scraping whatever is not cached or, as
+/// `policy` tells, is stale. Pages are fetched concurrently by `scheduler` and written through
+/// `store` as they arrive.
+///
+/// Subjects not offered, whose page cannot be fetched or parsed, or that would need scraping
+/// while offline, are reported and left without classes, recording why in the timesheet. A
+/// page that cannot be fetched is cached as such, and stale classes cached before are used
+/// instead if there are any. Fresh classes are kept when only the credits or prerequisites
+/// could not be fetched.
 pub fn build_timesheet<'a>(
     subjects: &Vec<Subject>,
     semester: &Semester,
     credits_map: &mut HashMap<&'a str, u8>,
-    cache_dir: &'a str,
-) -> Timesheet<'static> {
-    let mut table: HashMap<&str, Vec<Class>> = HashMap::new();
-    let mut scrape_classes: bool;
-    let mut scrape_credits: bool;
+    prerequisites_map: &mut HashMap<&'a str, Prerequisites>,
+    store: &dyn Store,
+    scheduler: &Scheduler,
+    policy: &CachePolicy,
+) -> Result<Timesheet<'static>, ScrapeError> {
+    let now = OffsetDateTime::now_utc();
+    let refresh = policy.mode == CacheMode::Refresh;
+    let mut ts = Timesheet {
+        table: HashMap::new(),
+        excluded: HashMap::new(),
+    };
+    // What each subject to be scraped needs: classes, credits and prerequisites
+    let mut pending: HashMap<&str, (bool, bool, bool)> = HashMap::new();
+    // Classes to fall back on if their page cannot be fetched
+    let mut stale: HashMap<&str, ClassesEntry> = HashMap::new();
+    let mut to_fetch = vec![];
     for subject in subjects {
-        scrape_classes = false;
-        scrape_credits = false;
-        if let Some(classes) = load_classes(cache_dir, subject, semester) {
-            table.insert(subject.code, classes);
-        } else {
-            scrape_classes = true;
-            println!(
-                "No cached classes for subject {} in semester {}. Scraping...",
-                subject.code, semester
-            );
-        }
-        if let Some(credits) = load_credits(cache_dir, subject) {
-            credits_map.insert(subject.code, credits);
-        } else {
-            scrape_credits = true;
-            println!(
-                "No cached credits for subject {} in semester {}. Scraping...",
-                subject.code, semester
-            );
-        }
-        if scrape_classes || scrape_credits {
-            thread::sleep(time::Duration::from_millis(500));
-            let resp = reqwest::get(
-                format!(
-                    "https://www.dac.unicamp.br/portal/caderno-de-horarios/{}/{}/S/G/{}/{}",
-                    semester.year, semester.semester, subject.institute, subject.code,
-                )
-                .as_str(),
-            )
-            .unwrap();
-            if !resp.status().is_success() {
-                if scrape_classes {
-                    table.insert(subject.code, Vec::new());
-                    save_classes(
-                        cache_dir,
-                        subject,
+        let mut scrape_classes = false;
+        let mut scrape_credits = false;
+        let mut scrape_prerequisites = false;
+        match store.load_classes(subject, semester)? {
+            Some(entry) if policy.is_fresh(&entry, semester, now) => {
+                let unknown = entry.offering == Offering::Unknown;
+                if unknown {
+                    println!(
+                        "Subject {} could not be fetched for semester {} {}. Skipping it.",
+                        subject.code,
                         semester,
-                        table.get(subject.code).unwrap(),
+                        entry.scraped_at.map_or("before".to_string(), |t| format!(
+                            "{} minutes ago",
+                            (now - t).whole_minutes()
+                        ))
                     );
                 }
-                continue;
+                insert_entry(&mut ts, subject.code, entry);
+                // Not even its credits are scraped until the failure expires
+                if unknown {
+                    continue;
+                }
             }
-            let document = Document::from_read(resp).unwrap();
-            if scrape_classes {
-                table.insert(subject.code, Vec::new());
-                for turma in document
-                    .find(predicate::Class("turma"))
-                    .flat_map(|x| x.find(predicate::Class("panel-body")))
-                    .flat_map(|x| x.find(predicate::Class("horariosFormatado")))
-                {
-                    let slots = Class(
-                        turma
-                            .find(predicate::Name("li"))
-                            .map(|x| {
-                                Slot::new(
-                                    x.find(predicate::Class("diaSemana")).next().unwrap().text(),
-                                    x.find(predicate::Class("horarios")).next().unwrap().text(),
-                                )
-                            })
-                            .collect::<Vec<_>>(),
+            Some(entry) if !refresh => {
+                scrape_classes = true;
+                println!(
+                    "Cached classes for subject {} in semester {} are stale (scraped {}). \
+                     Scraping...",
+                    subject.code,
+                    semester,
+                    entry
+                        .scraped_at
+                        .map_or("at an unknown time".to_string(), |t| format!(
+                            "{} hours ago",
+                            (now - t).whole_hours()
+                        ))
+                );
+                stale.insert(subject.code, entry);
+            }
+            Some(entry) => {
+                scrape_classes = true;
+                println!(
+                    "Refreshing subject {} in semester {}...",
+                    subject.code, semester
+                );
+                stale.insert(subject.code, entry);
+            }
+            None => {
+                scrape_classes = true;
+                if policy.mode == CacheMode::Normal {
+                    println!(
+                        "No cached classes for subject {} in semester {}. Scraping...",
+                        subject.code, semester
                     );
-                    table.get_mut(subject.code).unwrap().push(slots);
                 }
-                save_classes(
-                    cache_dir,
-                    subject,
-                    semester,
-                    table.get(subject.code).unwrap(),
+            }
+        }
+        if !credits_map.contains_key(subject.code) {
+            if let Some(credits) = store.load_credits(subject)?.filter(|_| !refresh) {
+                credits_map.insert(subject.code, credits);
+            } else {
+                scrape_credits = true;
+                if policy.mode == CacheMode::Normal {
+                    println!(
+                        "No cached credits for subject {} in semester {}. Scraping...",
+                        subject.code, semester
+                    );
+                }
+            }
+        }
+        if !prerequisites_map.contains_key(subject.code) {
+            if let Some(prerequisites) = store.load_prerequisites(subject)?.filter(|_| !refresh) {
+                prerequisites_map.insert(subject.code, prerequisites);
+            } else {
+                scrape_prerequisites = true;
+                if policy.mode == CacheMode::Normal {
+                    println!(
+                        "No cached prerequisites for subject {} in semester {}. Scraping...",
+                        subject.code, semester
+                    );
+                }
+            }
+        }
+        if !(scrape_classes || scrape_credits || scrape_prerequisites) {
+            continue;
+        }
+        if policy.mode == CacheMode::Offline {
+            if scrape_classes || scrape_credits {
+                println!(
+                    "Subject {} is not fully cached for semester {} and running offline. \
+                     Skipping it.",
+                    subject.code, semester
+                );
+                ts.exclude(subject.code, Exclusion::NotCached);
+            } else {
+                // Caches written before prerequisites were scraped have none
+                println!(
+                    "No cached prerequisites for subject {} and running offline. Assuming it has \
+                     none.",
+                    subject.code
                 );
+                prerequisites_map.insert(subject.code, Prerequisites::default());
+            }
+            continue;
+        }
+        pending.insert(
+            subject.code,
+            (scrape_classes, scrape_credits, scrape_prerequisites),
+        );
+        to_fetch.push(subject);
+    }
+
+    scheduler.fetch_all(
+        &to_fetch,
+        semester,
+        |subject, page| -> Result<_, ScrapeError> {
+            let (scrape_classes, scrape_credits, scrape_prerequisites) = pending[subject.code];
+            let source = scheduler.source(subject, semester);
+            let page = match page {
+                Ok(Some(page)) => page,
+                page if !scrape_classes => {
+                    // The cached classes are fresh, only credits or prerequisites are missing, so
+                    // they are kept even if the page is gone
+                    let reason = page.err().unwrap_or_else(|| "page not found".to_string());
+                    if credits_map.contains_key(subject.code) {
+                        println!(
+                            "Could not fetch the prerequisites of subject {} in semester {} \
+                             ({}).",
+                            subject.code, semester, reason
+                        );
+                    } else {
+                        println!(
+                            "Could not fetch the credits of subject {} in semester {} ({}). \
+                             Skipping it.",
+                            subject.code, semester, reason
+                        );
+                        ts.exclude(subject.code, Exclusion::Unknown(Some(reason)));
+                    }
+                    return Ok(());
+                }
+                Err(reason) => {
+                    match stale.remove(subject.code) {
+                        Some(entry)
+                            if entry.offering != Offering::Unknown
+                                && credits_map.contains_key(subject.code) =>
+                        {
+                            println!(
+                                "Could not fetch subject {} in semester {} ({}). Using the stale \
+                             cached classes.",
+                                subject.code, semester, reason
+                            );
+                            insert_entry(&mut ts, subject.code, entry);
+                        }
+                        _ => {
+                            println!(
+                                "Could not fetch subject {} in semester {} ({}). Skipping it.",
+                                subject.code, semester, reason
+                            );
+                            let entry = ClassesEntry {
+                                error: Some(reason),
+                                ..ClassesEntry::scraped(&source, Offering::Unknown, vec![])
+                            };
+                            store.save_classes(subject, semester, &entry)?;
+                            insert_entry(&mut ts, subject.code, entry);
+                        }
+                    }
+                    return Ok(());
+                }
+                Ok(None) => {
+                    let entry = ClassesEntry::scraped(&source, Offering::NotOffered, vec![]);
+                    store.save_classes(subject, semester, &entry)?;
+                    insert_entry(&mut ts, subject.code, entry);
+                    return Ok(());
+                }
+            };
+            let document = Document::from(page.as_str());
+            if scrape_classes {
+                match parse_classes(&document, subject) {
+                    Ok(classes) => {
+                        let offering = if classes.is_empty() {
+                            Offering::NotOffered
+                        } else {
+                            Offering::Offered
+                        };
+                        let entry = ClassesEntry::scraped(&source, offering, classes);
+                        store.save_classes(subject, semester, &entry)?;
+                        insert_entry(&mut ts, subject.code, entry);
+                    }
+                    Err(e) => {
+                        println!("{}. Skipping subject {}.", e, subject.code);
+                        ts.exclude(subject.code, Exclusion::Unparseable(e.to_string()));
+                        return Ok(());
+                    }
+                }
             }
             if scrape_credits && !credits_map.contains_key(subject.code) {
-                let credits = document
-                    .find(predicate::Class("prop"))
-                    .find(|x| x.text() == "Créditos:")
-                    .unwrap()
-                    .next() // space
-                    .unwrap()
-                    .next() // span with value
-                    .unwrap()
-                    .text() // value
-                    .trim()
-                    .parse::<u8>()
-                    .unwrap();
-                credits_map.insert(subject.code, credits);
-                save_credits(cache_dir, subject, credits);
+                match parse_credits(&document, subject) {
+                    Ok(credits) => {
+                        credits_map.insert(subject.code, credits);
+                        store.save_credits(subject, credits)?;
+                    }
+                    Err(e) => {
+                        println!("{}. Skipping subject {}.", e, subject.code);
+                        ts.exclude(subject.code, Exclusion::Unparseable(e.to_string()));
+                        return Ok(());
+                    }
+                }
+            }
+            if scrape_prerequisites {
+                let prerequisites = parse_prerequisites(&document);
+                store.save_prerequisites(subject, &prerequisites)?;
+                prerequisites_map.insert(subject.code, prerequisites);
+            }
+            Ok(())
+        },
+    )?;
+    Ok(ts)
+}
+
+/// Adds the classes of `entry` to `ts`, or why there are none.
+fn insert_entry(ts: &mut Timesheet, code: &'static str, entry: ClassesEntry) {
+    match entry.offering {
+        Offering::Offered => {
+            ts.table.insert(code, entry.classes);
+        }
+        Offering::NotOffered => ts.exclude(code, Exclusion::NotOffered),
+        Offering::Unknown => ts.exclude(code, Exclusion::Unknown(entry.error)),
+    }
+}
+
+/// Parses every class listed in a caderno de horários page.
+pub fn parse_classes(document: &Document, subject: &Subject) -> Result<Vec<Class>, ScrapeError> {
+    let missing = |field| ScrapeError::MissingField {
+        subject: subject.code.to_string(),
+        field,
+    };
+    let invalid = |field, value: String| ScrapeError::InvalidField {
+        subject: subject.code.to_string(),
+        field,
+        value,
+    };
+    let mut classes = vec![];
+    for turma in document.find(predicate::Class("turma")) {
+        let code = prop_value(turma.find(predicate::Class("prop")), "Turma:").unwrap_or_default();
+        let professors = turma
+            .find(predicate::Class("docentes"))
+            .flat_map(|x| x.find(predicate::Name("li")))
+            .map(|x| x.text().trim().to_string())
+            .filter(|x| !x.is_empty())
+            .collect::<Vec<_>>();
+        let vacancies = match prop_value(turma.find(predicate::Class("prop")), "Vagas:") {
+            Some(x) => Some(x.parse().map_err(|_| invalid("vacancies", x))?),
+            None => None,
+        };
+        let reserved = match prop_value(turma.find(predicate::Class("prop")), "Reservas:") {
+            Some(x) => Some(x.parse().map_err(|_| invalid("reserved seats", x))?),
+            None => None,
+        };
+        for horarios in turma
+            .find(predicate::Class("panel-body"))
+            .flat_map(|x| x.find(predicate::Class("horariosFormatado")))
+        {
+            let mut slots = vec![];
+            for li in horarios.find(predicate::Name("li")) {
+                let weekday = li
+                    .find(predicate::Class("diaSemana"))
+                    .next()
+                    .ok_or_else(|| missing("weekday"))?
+                    .text();
+                let duration = li
+                    .find(predicate::Class("horarios"))
+                    .next()
+                    .ok_or_else(|| missing("time"))?
+                    .text();
+                let slot = Slot::parse(&weekday, &duration).ok_or_else(|| {
+                    invalid(
+                        "time slot",
+                        format!("{} {}", weekday.trim(), duration.trim()),
+                    )
+                })?;
+                slots.push(Slot {
+                    room: li
+                        .find(predicate::Class("salaAula"))
+                        .next()
+                        .map(|x| x.text().trim().to_string())
+                        .filter(|x| !x.is_empty()),
+                    ..slot
+                });
+            }
+            classes.push(Class {
+                code: code.clone(),
+                professors: professors.clone(),
+                vacancies,
+                reserved,
+                slots,
+            });
+        }
+    }
+    Ok(classes)
+}
+
+/// Parses the number of credits of the subject of a caderno de horários page.
+pub fn parse_credits(document: &Document, subject: &Subject) -> Result<u8, ScrapeError> {
+    let value =
+        prop_value(document.find(predicate::Class("prop")), "Créditos:").ok_or_else(|| {
+            ScrapeError::MissingField {
+                subject: subject.code.to_string(),
+                field: "credits",
             }
+        })?;
+    value.parse().map_err(|_| ScrapeError::InvalidField {
+        subject: subject.code.to_string(),
+        field: "credits",
+        value,
+    })
+}
+
+/// Parses the prerequisites of the subject of a caderno de horários page. Pages
+/// without them (or with "Não há") yield no prerequisites.
+pub fn parse_prerequisites(document: &Document) -> Prerequisites {
+    prop_value(document.find(predicate::Class("prop")), "Pré-Requisitos:")
+        .map(|value| Prerequisites::from(value.as_str()))
+        .unwrap_or_default()
+}
+
+/// Finds the value printed next to the `prop` label (e.g. "Créditos:") among `props`.
+fn prop_value<'a>(mut props: impl Iterator<Item = Node<'a>>, label: &str) -> Option<String> {
+    let value = props
+        .find(|x| x.text().trim() == label)?
+        .next()? // space
+        .next()? // span with value
+        .text();
+    Some(value.trim().to_string())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::cache::YamlStore;
+    use crate::fetching::{FetchError, Fetcher, FixtureFetcher};
+    use crate::unicamp::Requirement;
+    use std::fs;
+    use std::path::Path;
+    use std::sync::atomic::{AtomicUsize, Ordering};
+    use std::sync::Arc;
+
+    const MC102: Subject = Subject::new("IC", "MC102");
+    const MC202: Subject = Subject::new("IC", "MC202");
+    const SEMESTER: Semester = Semester {
+        year: 2024,
+        semester: 1,
+    };
+
+    fn fixtures() -> FixtureFetcher {
+        FixtureFetcher::new(&Path::new(env!("CARGO_MANIFEST_DIR")).join("tests/fixtures"))
+    }
+
+    fn document(subject: &Subject) -> Document {
+        let page = fixtures().fetch(subject, &SEMESTER).unwrap().unwrap();
+        Document::from(page.as_str())
+    }
+
+    #[test]
+    fn parse_classes_reads_every_turma() {
+        let classes = parse_classes(&document(&MC102), &MC102).unwrap();
+        assert_eq!(classes.len(), 2);
+
+        assert_eq!(classes[0].code, "A");
+        assert_eq!(classes[0].professors, ["Ana Souza", "Bruno Lima"]);
+        assert_eq!(classes[0].vacancies, Some(60));
+        assert_eq!(classes[0].reserved, Some(10));
+        assert_eq!(
+            classes[0].slots,
+            [
+                Slot::parse("Segunda", "10:00 - 12:00").unwrap(),
+                Slot::parse("Quarta", "10:00 - 12:00").unwrap(),
+            ]
+        );
+        assert_eq!(classes[0].rooms(), ["CB01"]);
+
+        assert_eq!(classes[1].code, "B");
+        assert_eq!(classes[1].professors, ["Carla Dias"]);
+        assert_eq!(classes[1].vacancies, Some(45));
+        assert_eq!(classes[1].reserved, None);
+        assert_eq!(classes[1].slots[1].start, 2100);
+        assert_eq!(classes[1].slots[1].finish, 2300);
+        assert_eq!(classes[1].rooms(), ["CB05"]);
+    }
+
+    #[test]
+    fn parse_credits_reads_value() {
+        assert_eq!(parse_credits(&document(&MC102), &MC102), Ok(6));
+    }
+
+    #[test]
+    fn parse_classes_reports_invalid_slot() {
+        assert_eq!(
+            parse_classes(&document(&MC202), &MC202),
+            Err(ScrapeError::InvalidField {
+                subject: "MC202".to_string(),
+                field: "time slot",
+                value: "Segunda-feira 14:00 - 16:00".to_string(),
+            })
+        );
+    }
+
+    #[test]
+    fn parse_prerequisites_reads_alternatives() {
+        assert_eq!(
+            parse_prerequisites(&document(&MC102)),
+            Prerequisites::default()
+        );
+        let document = Document::from(
+            "<p><span class=\"prop\">Pré-Requisitos:</span> \
+             <span>MC102 *MA111 / F 128 AA200</span></p>",
+        );
+        assert_eq!(
+            parse_prerequisites(&document),
+            Prerequisites(vec![
+                vec![
+                    Requirement::Subject {
+                        code: "MC102".to_string(),
+                        partial: false,
+                    },
+                    Requirement::Subject {
+                        code: "MA111".to_string(),
+                        partial: true,
+                    },
+                ],
+                vec![
+                    Requirement::Subject {
+                        code: "F 128".to_string(),
+                        partial: false,
+                    },
+                    Requirement::Progress("AA200".to_string()),
+                ],
+            ])
+        );
+    }
+
+    #[test]
+    fn parse_credits_reports_invalid_and_missing_value() {
+        assert_eq!(
+            parse_credits(&document(&MC202), &MC202),
+            Err(ScrapeError::InvalidField {
+                subject: "MC202".to_string(),
+                field: "credits",
+                value: "seis".to_string(),
+            })
+        );
+        assert_eq!(
+            parse_credits(&Document::from("<html></html>"), &MC202),
+            Err(ScrapeError::MissingField {
+                subject: "MC202".to_string(),
+                field: "credits",
+            })
+        );
+    }
+
+    #[test]
+    fn build_timesheet_skips_unparseable_subjects() {
+        let cache_dir =
+            std::env::temp_dir().join(format!("unicamp_planner_{}", std::process::id()));
+        fs::create_dir_all(&cache_dir).unwrap();
+        let subjects = vec![MC102, MC202, Subject::new("IC", "MC322")];
+        let mut credits_map = HashMap::new();
+        let mut prerequisites_map = HashMap::new();
+        let ts = build_timesheet(
+            &subjects,
+            &SEMESTER,
+            &mut credits_map,
+            &mut prerequisites_map,
+            &YamlStore::new(&cache_dir),
+            &Scheduler::new(Box::new(fixtures())),
+            &CachePolicy::default(),
+        )
+        .unwrap();
+        fs::remove_dir_all(&cache_dir).unwrap();
+
+        assert_eq!(ts.table.keys().collect::<Vec<_>>(), [&"MC102"]);
+        assert_eq!(ts.table["MC102"].len(), 2);
+        assert_eq!(credits_map, HashMap::from([("MC102", 6)]));
+        assert_eq!(
+            prerequisites_map,
+            HashMap::from([("MC102", Prerequisites::default())])
+        );
+        assert_eq!(ts.excluded["MC322"], Exclusion::NotOffered);
+        assert!(matches!(ts.excluded["MC202"], Exclusion::Unparseable(_)));
+    }
+
+    /// Fails every fetch, counting them.
+    struct DownFetcher(Arc<AtomicUsize>);
+
+    impl Fetcher for DownFetcher {
+        fn fetch(&self, _: &Subject, _: &Semester) -> Result<Option<String>, FetchError> {
+            self.0.fetch_add(1, Ordering::Relaxed);
+            Err(FetchError {
+                reason: "HTTP 404".to_string(),
+                transient: false,
+            })
+        }
+
+        fn source(&self, _: &Subject, _: &Semester) -> String {
+            "down".to_string()
         }
     }
-    Timesheet { table }
+
+    #[test]
+    fn build_timesheet_remembers_failed_fetches() {
+        let cache_dir =
+            std::env::temp_dir().join(format!("unicamp_planner_down_{}", std::process::id()));
+        let store = YamlStore::new(&cache_dir);
+        let fetches = Arc::new(AtomicUsize::new(0));
+        let scheduler = Scheduler::new(Box::new(DownFetcher(fetches.clone())));
+        let build = || {
+            build_timesheet(
+                &vec![MC102],
+                &SEMESTER,
+                &mut HashMap::new(),
+                &mut HashMap::new(),
+                &store,
+                &scheduler,
+                &CachePolicy::default(),
+            )
+            .unwrap()
+        };
+        let first = build();
+        let second = build();
+        let entry = store.load_classes(&MC102, &SEMESTER).unwrap().unwrap();
+        fs::remove_dir_all(&cache_dir).unwrap();
+
+        let reason = Exclusion::Unknown(Some("HTTP 404".to_string()));
+        assert_eq!(first.excluded["MC102"], reason);
+        assert_eq!(second.excluded["MC102"], reason);
+        assert_eq!(entry.offering, Offering::Unknown);
+        assert_eq!(fetches.load(Ordering::Relaxed), 1);
+    }
+
+    #[test]
+    fn build_timesheet_offline_does_not_need_prerequisites() {
+        let cache_dir =
+            std::env::temp_dir().join(format!("unicamp_planner_offline_{}", std::process::id()));
+        let store = YamlStore::new(&cache_dir);
+        let fetches = Arc::new(AtomicUsize::new(0));
+        let scheduler = Scheduler::new(Box::new(DownFetcher(fetches.clone())));
+        let entry = ClassesEntry::scraped("saved", Offering::Offered, vec![Class::default()]);
+        store.save_classes(&MC102, &SEMESTER, &entry).unwrap();
+        store.save_credits(&MC102, 6).unwrap();
+        store.save_classes(&MC202, &SEMESTER, &entry).unwrap();
+        let mut credits_map = HashMap::new();
+        let mut prerequisites_map = HashMap::new();
+        let ts = build_timesheet(
+            &vec![MC102, MC202],
+            &SEMESTER,
+            &mut credits_map,
+            &mut prerequisites_map,
+            &store,
+            &scheduler,
+            &CachePolicy {
+                mode: CacheMode::Offline,
+                ..Default::default()
+            },
+        )
+        .unwrap();
+        fs::remove_dir_all(&cache_dir).unwrap();
+
+        assert_eq!(ts.table["MC102"].len(), 1);
+        assert_eq!(credits_map, HashMap::from([("MC102", 6)]));
+        assert_eq!(
+            prerequisites_map,
+            HashMap::from([("MC102", Prerequisites::default())])
+        );
+        // Credits are still needed
+        assert_eq!(ts.excluded["MC202"], Exclusion::NotCached);
+        assert_eq!(fetches.load(Ordering::Relaxed), 0);
+    }
+
+    #[test]
+    fn build_timesheet_keeps_fresh_classes_when_credits_cannot_be_fetched() {
+        let cache_dir =
+            std::env::temp_dir().join(format!("unicamp_planner_fresh_{}", std::process::id()));
+        let store = YamlStore::new(&cache_dir);
+        let scheduler = Scheduler::new(Box::new(DownFetcher(Arc::new(AtomicUsize::new(0)))));
+        let entry = ClassesEntry::scraped("saved", Offering::Offered, vec![Class::default()]);
+        store.save_classes(&MC102, &SEMESTER, &entry).unwrap();
+        let build = || {
+            build_timesheet(
+                &vec![MC102],
+                &SEMESTER,
+                &mut HashMap::new(),
+                &mut HashMap::new(),
+                &store,
+                &scheduler,
+                &CachePolicy::default(),
+            )
+            .unwrap()
+        };
+        let without_credits = build();
+        store.save_credits(&MC102, 6).unwrap();
+        let without_prerequisites = build();
+        let saved = store.load_classes(&MC102, &SEMESTER).unwrap().unwrap();
+        fs::remove_dir_all(&cache_dir).unwrap();
+
+        assert_eq!(
+            without_credits.excluded["MC102"],
+            Exclusion::Unknown(Some("HTTP 404".to_string()))
+        );
+        assert_eq!(without_prerequisites.table["MC102"].len(), 1);
+        assert_eq!(saved.offering, Offering::Offered);
+        assert_eq!(saved.classes.len(), 1);
+    }
+
+    #[test]
+    fn build_timesheet_keeps_fresh_classes_when_the_page_is_gone() {
+        let cache_dir =
+            std::env::temp_dir().join(format!("unicamp_planner_gone_{}", std::process::id()));
+        let store = YamlStore::new(&cache_dir);
+        // The fixtures have no pages for 2s2024
+        let semester = Semester::from("2s2024");
+        let scheduler = Scheduler::new(Box::new(fixtures()));
+        let entry = ClassesEntry::scraped("saved", Offering::Offered, vec![Class::default()]);
+        store.save_classes(&MC102, &semester, &entry).unwrap();
+        let build = || {
+            build_timesheet(
+                &vec![MC102],
+                &semester,
+                &mut HashMap::new(),
+                &mut HashMap::new(),
+                &store,
+                &scheduler,
+                &CachePolicy::default(),
+            )
+            .unwrap()
+        };
+        let without_credits = build();
+        store.save_credits(&MC102, 6).unwrap();
+        let without_prerequisites = build();
+        let saved = store.load_classes(&MC102, &semester).unwrap().unwrap();
+        fs::remove_dir_all(&cache_dir).unwrap();
+
+        assert_eq!(
+            without_credits.excluded["MC102"],
+            Exclusion::Unknown(Some("page not found".to_string()))
+        );
+        assert_eq!(without_prerequisites.table["MC102"].len(), 1);
+        assert!(without_prerequisites.excluded.is_empty());
+        assert_eq!(saved.offering, Offering::Offered);
+    }
 }
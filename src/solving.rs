@@ -1,12 +1,19 @@
 use itertools::Itertools;
 
-use crate::unicamp::{Class, Slot, Timesheet};
+use crate::scoring::Scoring;
+use crate::unicamp::{Class, Prerequisites, Slot, Timesheet};
+use std::cmp::{Ordering, Reverse};
 use std::collections::HashMap;
+use std::fmt;
+use std::ops::RangeInclusive;
+use std::sync::atomic::{self, AtomicUsize};
+use std::sync::{Mutex, OnceLock};
+use std::thread;
+use std::time::{Duration, Instant};
 
-#[derive(Clone, Debug)]
+#[derive(Clone, Debug, Default)]
 struct ScheduleInProgress<'a> {
     pub table: HashMap<&'a str, Class>,
-    pub finished: bool,
     pub cr_count: u8,
 }
 
@@ -14,15 +21,238 @@ struct ScheduleInProgress<'a> {
 struct SolutionInProgress<'a> {
     pub schedules: Vec<ScheduleInProgress<'a>>,
     pub satisfied: Vec<&'a str>,
-    pub goal: u8,
+    /// Electives this solution chose not to take.
+    pub declined: Vec<&'a str>,
 }
 
 impl<'a> SolutionInProgress<'a> {
-    pub fn finished(&self) -> bool {
-        self.schedules.iter().all(|sc| sc.finished)
+    /// Whether every required subject is taken and every elective group is met.
+    pub fn solved(&self, problem: &Problem<'a, '_>) -> bool {
+        problem
+            .required
+            .iter()
+            .all(|subject| self.satisfied.contains(subject))
+            && problem
+                .goals
+                .electives
+                .iter()
+                .all(|group| problem.is_met(group, &self.satisfied))
+    }
+    /// Subjects of the plan this solution has neither taken nor chosen to leave out, nor needs
+    /// for an elective group it already met.
+    pub fn remaining(&self, problem: &Problem<'a, '_>) -> Vec<&'a str> {
+        problem
+            .subjects
+            .iter()
+            .copied()
+            .filter(|subject| {
+                !self.satisfied.contains(subject)
+                    && !self.declined.contains(subject)
+                    && !problem
+                        .group_of(subject)
+                        .is_some_and(|group| problem.is_met(group, &self.satisfied))
+            })
+            .collect()
+    }
+    /// Sum of the priorities of the subjects taken.
+    pub fn weight(&self, problem: &Problem<'a, '_>) -> u32 {
+        self.satisfied
+            .iter()
+            .map(|subject| problem.priority(subject) as u32)
+            .sum()
+    }
+    /// Subjects taken in the semesters before `schedule_idx`.
+    pub fn earlier(&self, schedule_idx: usize) -> Vec<&'a str> {
+        self.schedules[..schedule_idx]
+            .iter()
+            .flat_map(|sc| sc.table.keys().copied())
+            .collect()
     }
-    pub fn solved(&self) -> bool {
-        self.satisfied.len() as u8 == self.goal
+}
+
+/// Bounds on the credits taken in each semester.
+#[derive(Clone, Debug, Default)]
+pub struct CreditLimits {
+    pub min: u8,
+    pub max: u8,
+    /// Caps replacing `max` in some semesters, keyed by their index in the plan.
+    pub caps: HashMap<usize, u8>,
+}
+
+impl CreditLimits {
+    pub fn max(&self, schedule_idx: usize) -> u8 {
+        *self.caps.get(&schedule_idx).unwrap_or(&self.max)
+    }
+}
+
+/// What the plan asks of a subject.
+#[derive(Clone, Debug)]
+pub struct SubjectGoal {
+    /// Among subjects with as many classes to choose from, those of higher priority are taken
+    /// first, and solutions taking more priority in all rank higher.
+    pub priority: u8,
+    /// Whether the plan is solved without the subject.
+    pub optional: bool,
+    /// Indices in the plan of the semesters the subject may be taken in.
+    pub semesters: RangeInclusive<usize>,
+}
+
+/// Subjects of which at least `min_count`, adding up to at least `min_credits`, must be taken.
+#[derive(Clone, Debug)]
+pub struct ElectiveGroup<'a> {
+    pub name: String,
+    pub subjects: Vec<&'a str>,
+    pub min_count: usize,
+    pub min_credits: u16,
+}
+
+/// What the plan asks for. Subjects missing from `subjects` may be taken in any semester, and
+/// those in none of `electives` are required.
+#[derive(Clone, Debug, Default)]
+pub struct Goals<'a> {
+    pub subjects: HashMap<&'a str, SubjectGoal>,
+    pub electives: Vec<ElectiveGroup<'a>>,
+}
+
+/// Limits every semester must respect.
+#[derive(Clone, Debug, Default)]
+pub struct Constraints {
+    pub credits: CreditLimits,
+    /// Windows no class may overlap, in any semester.
+    pub blocked: Vec<Slot>,
+}
+
+/// What the search needs to know about the plan, shared by every semester.
+struct Problem<'a, 'p> {
+    timesheets: &'p [Timesheet<'a>],
+    /// Subjects with classes in some semester.
+    subjects: Vec<&'a str>,
+    /// Subjects with classes in some semester that are neither optional nor in an elective
+    /// group.
+    required: Vec<&'a str>,
+    goals: &'p Goals<'a>,
+    cr_map: &'p HashMap<&'a str, u8>,
+    prerequisites: &'p HashMap<&'a str, Prerequisites>,
+    credits: &'p CreditLimits,
+    blocked: &'p [Slot],
+    scoring: &'p Scoring,
+}
+
+impl<'a, 'p> Problem<'a, 'p> {
+    /// Whether `subject` may be taken in the `schedule_idx`-th semester.
+    fn allows(&self, subject: &str, schedule_idx: usize) -> bool {
+        self.goals
+            .subjects
+            .get(subject)
+            .is_none_or(|goal| goal.semesters.contains(&schedule_idx))
+    }
+
+    fn priority(&self, subject: &str) -> u8 {
+        self.goals
+            .subjects
+            .get(subject)
+            .map_or(1, |goal| goal.priority)
+    }
+
+    fn group_of(&self, subject: &str) -> Option<&'p ElectiveGroup<'a>> {
+        self.goals
+            .electives
+            .iter()
+            .find(|group| group.subjects.contains(&subject))
+    }
+
+    /// Whether taking `taken` meets `group`.
+    fn is_met(&self, group: &ElectiveGroup, taken: &[&str]) -> bool {
+        let members = group
+            .subjects
+            .iter()
+            .filter(|subject| taken.contains(subject))
+            .collect::<Vec<_>>();
+        let credits = members
+            .iter()
+            .map(|subject| self.cr_map.get(*subject).map_or(0, |cr| *cr as u16))
+            .sum::<u16>();
+        members.len() >= group.min_count && credits >= group.min_credits
+    }
+
+    fn is_optional(&self, subject: &str) -> bool {
+        self.goals
+            .subjects
+            .get(subject)
+            .is_some_and(|goal| goal.optional)
+    }
+
+    /// Whether `sol` may leave out `subject`, being optional or not needed to meet its elective
+    /// group.
+    fn may_decline(&self, subject: &str, sol: &SolutionInProgress<'a>) -> bool {
+        if self.is_optional(subject) {
+            return true;
+        }
+        let Some(group) = self.group_of(subject) else {
+            return false;
+        };
+        let available = group
+            .subjects
+            .iter()
+            .copied()
+            .filter(|s| *s != subject && self.subjects.contains(s) && !sol.declined.contains(s))
+            .collect::<Vec<_>>();
+        self.is_met(group, &available)
+    }
+
+    /// Whether `subject` has classes in a semester from `schedule_idx` on that it may be taken
+    /// in, within the credit cap.
+    fn takeable(&self, subject: &str, schedule_idx: usize) -> bool {
+        self.timesheets
+            .iter()
+            .enumerate()
+            .skip(schedule_idx)
+            .any(|(idx, ts)| {
+                ts.table
+                    .get(subject)
+                    .is_some_and(|classes| classes.iter().any(|c| self.fits(c, &HashMap::new())))
+                    && self.allows(subject, idx)
+                    && self.cr_map.get(subject).unwrap_or(&0) <= &self.credits.max(idx)
+            })
+    }
+
+    /// Best rank any solution going on from `sol` in the `schedule_idx`-th semester may reach.
+    fn bound(&self, sol: &SolutionInProgress<'a>, schedule_idx: usize) -> Rank {
+        let takeable = sol
+            .remaining(self)
+            .into_iter()
+            .filter(|subject| self.takeable(subject, schedule_idx))
+            .collect::<Vec<_>>();
+        let reachable = [&sol.satisfied[..], &takeable[..]].concat();
+        let done = sol.schedules[..schedule_idx]
+            .iter()
+            .map(|sc| &sc.table)
+            .collect::<Vec<_>>();
+        let more = takeable
+            .iter()
+            .any(|subject| self.takeable(subject, schedule_idx + 1));
+        Rank {
+            solved: self.required.iter().all(|s| reachable.contains(s))
+                && self
+                    .goals
+                    .electives
+                    .iter()
+                    .all(|group| self.is_met(group, &reachable)),
+            weight: sol.weight(self)
+                + takeable
+                    .iter()
+                    .map(|subject| self.priority(subject) as u32)
+                    .sum::<u32>(),
+            score: self
+                .scoring
+                .upper_bound(&done, &sol.schedules[schedule_idx].table, more),
+        }
+    }
+
+    fn fits(&self, class: &Class, current: &HashMap<&str, Class>) -> bool {
+        class.slots.iter().all(|slot| {
+            !does_slot_conflict(slot, current) && !self.blocked.iter().any(|b| slot.overlaps(b))
+        })
     }
 }
 
@@ -37,228 +267,707 @@ pub struct Schedule<'a> {
 pub struct Solution<'a> {
     pub schedules: Vec<Schedule<'a>>,
     pub score: f32,
+    /// Unweighted value of each scoring criterion.
+    pub breakdown: Vec<(&'static str, f32)>,
+    /// Subjects taken of each elective group, by group name.
+    pub electives: Vec<(String, Vec<&'a str>)>,
+    /// Subjects of the plan left out, other than electives of groups that were met, with why.
+    pub missing: Vec<(&'a str, String)>,
+    /// How the search that found the solution went.
+    pub search: SearchStats,
+}
+
+/// When the search gives up looking for better solutions.
+#[derive(Clone, Debug)]
+pub struct SearchLimits {
+    /// How many of the best solutions to return.
+    pub keep: usize,
+    pub time_limit: Option<Duration>,
+    /// Stop once this many solutions were found, counting those not kept.
+    pub max_solutions: Option<usize>,
 }
 
+impl Default for SearchLimits {
+    fn default() -> Self {
+        SearchLimits {
+            keep: 5,
+            time_limit: None,
+            max_solutions: None,
+        }
+    }
+}
+
+/// Limit that stopped a search before it was done.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Stop {
+    TimeLimit,
+    MaxSolutions,
+}
+
+impl fmt::Display for Stop {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Stop::TimeLimit => write!(f, "the time limit"),
+            Stop::MaxSolutions => write!(f, "the solution limit"),
+        }
+    }
+}
+
+#[derive(Clone, Copy, Debug, Default, PartialEq)]
+pub struct SearchStats {
+    pub branches: usize,
+    /// Branches cut off for not being able to beat the `keep`-th best solution.
+    pub pruned: usize,
+    /// Branches ended by a semester under the minimum credits.
+    pub dropped: usize,
+    pub found: usize,
+    pub elapsed: Duration,
+    /// Limit the search stopped at, in which case better solutions may have been missed.
+    pub stopped: Option<Stop>,
+}
+
+/// Why a subject could not be taken in a semester of a solution.
+#[derive(Clone, Debug, PartialEq)]
+enum Obstacle<'a> {
+    NotOffered,
+    OutsideWindow,
+    Prerequisites,
+    /// Every class overlaps one of `subjects` or, if `blocked`, a blocked window.
+    Conflicts {
+        subjects: Vec<&'a str>,
+        blocked: bool,
+    },
+    /// Taking it would bring the semester to `credits`, over `cap`.
+    Credits {
+        credits: u8,
+        cap: u8,
+    },
+}
+
+impl fmt::Display for Obstacle<'_> {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Obstacle::NotOffered => write!(f, "not offered"),
+            Obstacle::OutsideWindow => write!(f, "not allowed by the plan"),
+            Obstacle::Prerequisites => write!(f, "prerequisites not met"),
+            Obstacle::Conflicts { subjects, blocked } => {
+                let mut names = subjects.iter().map(|s| s.to_string()).collect::<Vec<_>>();
+                if *blocked {
+                    names.push("a blocked window".to_string());
+                }
+                write!(f, "every class overlaps {}", names.join(" or "))
+            }
+            Obstacle::Credits { credits, cap } => {
+                write!(f, "would take {} credits, over the cap of {}", credits, cap)
+            }
+        }
+    }
+}
+
+/// Plans the subjects over one semester per timesheet, in order, and returns the `limits.keep`
+/// best solutions, best first. A solution ends once it can take no more subjects. Solutions that
+/// take every required subject are kept if there are any, then those taking more priority in
+/// all, then those of higher score.
+///
+/// The search goes depth first, at each step choosing a class of the subject with the fewest
+/// classes that fit, or leaving the subject out if the plan allows it, and moves on to the next
+/// semester once nothing more fits. It never postpones a subject that fits to a later semester,
+/// so the solutions are only the best among plans that fill each semester as far as they can: a
+/// plan spreading the subjects over more semesters, say for more free days, is never found. It
+/// cuts off branches that cannot beat the `keep`-th best solution found so far, so it only ever
+/// holds one solution in progress per worker and the best ones. Its first branches are shared
+/// between a worker per core, and equally ranked solutions are ordered by the classes they take,
+/// so every run returns the same solutions in the same order. Past `limits`, it stops and returns
+/// the best solutions found until then.
 pub fn solve_all<'a>(
-    ts1: &Timesheet<'a>,
-    ts2: &Timesheet<'a>,
+    timesheets: &[Timesheet<'a>],
+    goals: &Goals<'a>,
     cr_map: &HashMap<&'a str, u8>,
-    cr_max: u8,
+    prerequisites: &HashMap<&'a str, Prerequisites>,
+    constraints: &Constraints,
+    scoring: &Scoring,
+    limits: &SearchLimits,
 ) -> Vec<Solution<'a>> {
-    let mut solutions: Vec<SolutionInProgress<'_>> = vec![];
     let mut subjects = vec![];
-    for ts in [ts1, ts2] {
-        for subject in ts.table.keys() {
-            if !subjects.contains(subject) {
+    for ts in timesheets {
+        for (subject, classes) in ts.table.iter() {
+            if !classes.is_empty() && !subjects.contains(subject) {
                 subjects.push(*subject);
             }
         }
     }
-    let mut schedule_idx = 0;
-    loop {
-        let in_progress = solutions
+    subjects.sort();
+    for line in exclusion_report(timesheets, &subjects) {
+        println!("{}", line);
+    }
+    let required = subjects
+        .iter()
+        .copied()
+        .filter(|subject| {
+            !goals.electives.iter().any(|g| g.subjects.contains(subject))
+                && !goals
+                    .subjects
+                    .get(subject)
+                    .is_some_and(|goal| goal.optional)
+        })
+        .collect();
+    let problem = Problem {
+        timesheets,
+        subjects,
+        required,
+        goals,
+        cr_map,
+        prerequisites,
+        credits: &constraints.credits,
+        blocked: &constraints.blocked,
+        scoring,
+    };
+    for group in goals.electives.iter() {
+        if !problem.is_met(group, &problem.subjects) {
+            println!(
+                "Elective group {} cannot be met by the subjects with classes: {}.",
+                group.name,
+                group
+                    .subjects
+                    .iter()
+                    .filter(|subject| problem.subjects.contains(subject))
+                    .join(", ")
+            );
+        }
+    }
+    let start = Instant::now();
+    let best = Best {
+        keep: limits.keep,
+        found: Mutex::new(vec![]),
+        count: AtomicUsize::new(0),
+        max_solutions: limits.max_solutions,
+        deadline: limits.time_limit.map(|limit| start + limit),
+        stop: OnceLock::new(),
+    };
+    let mut stats = SearchStats::default();
+    if limits.keep > 0 && !timesheets.is_empty() {
+        let root = SolutionInProgress {
+            schedules: vec![ScheduleInProgress::default()],
+            satisfied: vec![],
+            declined: vec![],
+        };
+        let workers = thread::available_parallelism().map_or(1, |n| n.get());
+        // Explore the first choices on this thread, a level at a time, until there are enough
+        // branches to share between the workers
+        let mut tasks = vec![(root, 0)];
+        while !tasks.is_empty() && tasks.len() < workers * 8 {
+            let mut splitter = Search::new(&best, Some(1));
+            for (mut sol, schedule_idx) in tasks {
+                splitter.branch(&mut sol, schedule_idx, 0, &problem);
+            }
+            stats.add(&splitter.stats);
+            tasks = splitter.tasks;
+        }
+        let next = AtomicUsize::new(0);
+        thread::scope(|scope| {
+            let handles = (0..workers.min(tasks.len()))
+                .map(|_| {
+                    scope.spawn(|| {
+                        let mut search = Search::new(&best, None);
+                        while let Some((sol, schedule_idx)) =
+                            tasks.get(next.fetch_add(1, atomic::Ordering::Relaxed))
+                        {
+                            search.threshold = best.threshold();
+                            search.branch(&mut sol.clone(), *schedule_idx, 0, &problem);
+                        }
+                        search.stats
+                    })
+                })
+                .collect_vec();
+            for handle in handles {
+                stats.add(&handle.join().unwrap());
+            }
+        });
+    }
+    stats.elapsed = start.elapsed();
+    stats.stopped = best.stop.get().copied();
+    println!("{}.", stats);
+    if let Some(stop) = stats.stopped {
+        println!(
+            "Stopped at {}, so better solutions may have been missed.",
+            stop
+        );
+    }
+    if stats.found == 0 && stats.dropped > 0 {
+        println!(
+            "No solution keeps every semester at or above {} credits.",
+            constraints.credits.min
+        );
+    }
+    let mut best = best.found.into_inner().unwrap();
+    if best.iter().any(|found| found.rank.solved) {
+        best.retain(|found| found.rank.solved);
+    } else if !best.is_empty() {
+        println!("No solution takes every required subject, keeping those that take the most.");
+    }
+    best.iter()
+        .map(|Found { sol, .. }| {
+            let tables = sol.schedules.iter().map(|sc| &sc.table).collect_vec();
+            let (score, breakdown) = scoring.score_solution(&tables);
+            Solution {
+                schedules: sol
+                    .schedules
+                    .iter()
+                    .map(|sc| Schedule {
+                        table: sc.table.to_owned(),
+                        score: scoring.score_semester(&sc.table),
+                        cr_count: sc.cr_count,
+                    })
+                    .collect_vec(),
+                score,
+                breakdown,
+                electives: goals
+                    .electives
+                    .iter()
+                    .map(|group| {
+                        let taken = group
+                            .subjects
+                            .iter()
+                            .copied()
+                            .filter(|subject| sol.satisfied.contains(subject))
+                            .sorted()
+                            .collect();
+                        (group.name.clone(), taken)
+                    })
+                    .collect(),
+                missing: problem
+                    .subjects
+                    .iter()
+                    .copied()
+                    .filter(|subject| {
+                        !sol.satisfied.contains(subject)
+                            && !problem
+                                .group_of(subject)
+                                .is_some_and(|group| problem.is_met(group, &sol.satisfied))
+                    })
+                    .sorted()
+                    .map(|subject| (subject, explain(subject, sol, &problem)))
+                    .collect(),
+                search: stats,
+            }
+        })
+        .collect_vec()
+}
+
+/// How solutions compare: those that take every required subject first, then those taking more
+/// priority, then those of higher score.
+#[derive(Clone, Copy, Debug)]
+struct Rank {
+    solved: bool,
+    weight: u32,
+    score: f32,
+}
+
+impl Rank {
+    fn compare(&self, other: &Rank) -> Ordering {
+        self.solved
+            .cmp(&other.solved)
+            .then(self.weight.cmp(&other.weight))
+            .then(self.score.total_cmp(&other.score))
+    }
+}
+
+/// A solution found by the search.
+struct Found<'a> {
+    rank: Rank,
+    /// Classes taken as (semester index, subject, turma, sorted slots), sorted, breaking ties
+    /// between equally ranked solutions. Turmas alone may be empty or shared by several classes.
+    key: Vec<(usize, &'a str, String, Vec<Slot>)>,
+    sol: SolutionInProgress<'a>,
+}
+
+impl<'a> Found<'a> {
+    fn new(rank: Rank, sol: &SolutionInProgress<'a>) -> Self {
+        let key = sol
+            .schedules
             .iter()
-            .filter(|sol| !sol.solved())
-            .collect::<Vec<_>>();
-        if !solutions.is_empty() && in_progress.is_empty() {
-            break;
+            .enumerate()
+            .flat_map(|(idx, sc)| {
+                sc.table.iter().map(move |(subject, class)| {
+                    let slots = class.slots.iter().cloned().sorted().collect();
+                    (idx, *subject, class.code.clone(), slots)
+                })
+            })
+            .sorted()
+            .collect();
+        Found {
+            rank,
+            key,
+            sol: sol.clone(),
         }
-        if schedule_idx % 2 == 0 {
-            solve_semester(ts1, &mut solutions, &subjects, cr_map, cr_max, schedule_idx);
-        } else {
-            solve_semester(ts2, &mut solutions, &subjects, cr_map, cr_max, schedule_idx);
+    }
+
+    /// Orders better solutions first.
+    fn order(&self, other: &Found) -> Ordering {
+        other
+            .rank
+            .compare(&self.rank)
+            .then_with(|| self.key.cmp(&other.key))
+    }
+}
+
+/// The best solutions found so far by every worker, best first, and whether to stop.
+struct Best<'a> {
+    keep: usize,
+    found: Mutex<Vec<Found<'a>>>,
+    /// Solutions found, counting those not kept.
+    count: AtomicUsize,
+    max_solutions: Option<usize>,
+    deadline: Option<Instant>,
+    stop: OnceLock<Stop>,
+}
+
+impl Best<'_> {
+    /// Rank a solution must reach to be kept, if `keep` solutions were found already.
+    fn threshold(&self) -> Option<Rank> {
+        let found = self.found.lock().unwrap();
+        found.get(self.keep - 1).map(|f| f.rank)
+    }
+}
+
+impl fmt::Display for SearchStats {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(
+            f,
+            "Searched {} branches in {:.2}s and found {} solutions, cutting off {} branches that \
+             could not beat them",
+            self.branches,
+            self.elapsed.as_secs_f32(),
+            self.found,
+            self.pruned
+        )
+    }
+}
+
+impl SearchStats {
+    fn add(&mut self, other: &SearchStats) {
+        self.branches += other.branches;
+        self.pruned += other.pruned;
+        self.dropped += other.dropped;
+        self.found += other.found;
+    }
+}
+
+/// A branch-and-bound search on one thread.
+struct Search<'a, 's> {
+    best: &'s Best<'a>,
+    /// Rank of the `keep`-th best solution when last looked at.
+    threshold: Option<Rank>,
+    /// Depth at which branches are set aside in `tasks` instead of explored, if any.
+    split: Option<usize>,
+    tasks: Vec<(SolutionInProgress<'a>, usize)>,
+    stats: SearchStats,
+}
+
+impl<'a, 's> Search<'a, 's> {
+    fn new(best: &'s Best<'a>, split: Option<usize>) -> Self {
+        Search {
+            best,
+            threshold: best.threshold(),
+            split,
+            tasks: vec![],
+            stats: SearchStats::default(),
         }
-        schedule_idx += 1;
     }
-    let mut solutions = solutions
+
+    /// Explores every way of going on with `sol` in the `schedule_idx`-th semester, `depth`
+    /// choices below where the search started, leaving it as it was.
+    fn branch(
+        &mut self,
+        sol: &mut SolutionInProgress<'a>,
+        schedule_idx: usize,
+        depth: usize,
+        problem: &Problem<'a, '_>,
+    ) {
+        if self.best.stop.get().is_some() {
+            return;
+        }
+        if self.split == Some(depth) {
+            self.tasks.push((sol.clone(), schedule_idx));
+            return;
+        }
+        self.stats.branches += 1;
+        if self.stats.branches.is_multiple_of(256) {
+            // Other workers may have found better solutions
+            self.threshold = self.best.threshold();
+            if self
+                .best
+                .deadline
+                .is_some_and(|deadline| Instant::now() >= deadline)
+            {
+                let _ = self.best.stop.set(Stop::TimeLimit);
+                return;
+            }
+        }
+        // Branches only as good as the threshold are kept, as they may win the tie
+        if self.threshold.is_some_and(|threshold| {
+            problem.bound(sol, schedule_idx).compare(&threshold) == Ordering::Less
+        }) {
+            self.stats.pruned += 1;
+            return;
+        }
+        let ts = &problem.timesheets[schedule_idx];
+        let Some(subject) = get_next_subject(ts, sol, schedule_idx, problem) else {
+            return self.next_semester(sol, schedule_idx, depth, problem);
+        };
+        let credits = *problem.cr_map.get(subject).unwrap();
+        let classes = ts.table.get(subject).unwrap();
+        for class in classes {
+            if !problem.fits(class, &sol.schedules[schedule_idx].table) {
+                continue;
+            }
+            let sc = &mut sol.schedules[schedule_idx];
+            sc.table.insert(subject, class.clone());
+            sc.cr_count += credits;
+            sol.satisfied.push(subject);
+            self.branch(sol, schedule_idx, depth + 1, problem);
+            sol.satisfied.pop();
+            let sc = &mut sol.schedules[schedule_idx];
+            sc.cr_count -= credits;
+            sc.table.remove(subject);
+        }
+        if problem.may_decline(subject, sol) {
+            sol.declined.push(subject);
+            self.branch(sol, schedule_idx, depth + 1, problem);
+            sol.declined.pop();
+        }
+    }
+
+    /// Goes on with `sol` in the semester after `schedule_idx`, whose classes are all chosen, or
+    /// ends it there.
+    fn next_semester(
+        &mut self,
+        sol: &mut SolutionInProgress<'a>,
+        schedule_idx: usize,
+        depth: usize,
+        problem: &Problem<'a, '_>,
+    ) {
+        let solved = sol.solved(problem);
+        // Only the semester that completes the plan may stay below the minimum
+        if !solved && sol.schedules[schedule_idx].cr_count < problem.credits.min {
+            self.stats.dropped += 1;
+            return;
+        }
+        let next = schedule_idx + 1;
+        if next < problem.timesheets.len()
+            && sol
+                .remaining(problem)
+                .iter()
+                .any(|subject| problem.takeable(subject, next))
+        {
+            sol.schedules.push(ScheduleInProgress::default());
+            self.branch(sol, next, depth + 1, problem);
+            sol.schedules.pop();
+            return;
+        }
+        self.stats.found += 1;
+        let count = self.best.count.fetch_add(1, atomic::Ordering::Relaxed) + 1;
+        if self.best.max_solutions.is_some_and(|max| count >= max) {
+            let _ = self.best.stop.set(Stop::MaxSolutions);
+        }
+        let tables = sol.schedules.iter().map(|sc| &sc.table).collect_vec();
+        let rank = Rank {
+            solved,
+            weight: sol.weight(problem),
+            score: problem.scoring.score_solution(&tables).0,
+        };
+        if self
+            .threshold
+            .is_some_and(|threshold| rank.compare(&threshold) == Ordering::Less)
+        {
+            return;
+        }
+        let found = Found::new(rank, sol);
+        let mut best = self.best.found.lock().unwrap();
+        let idx = best.partition_point(|other| other.order(&found) == Ordering::Less);
+        if idx < self.best.keep {
+            best.insert(idx, found);
+            best.truncate(self.best.keep);
+        }
+        self.threshold = best.get(self.best.keep - 1).map(|f| f.rank);
+    }
+}
+
+/// One line per subject left out of the plan for having no classes in any semester, telling
+/// why in each semester, e.g. "Left MC999 out of the plan: not offered in semesters 1, 2."
+fn exclusion_report(timesheets: &[Timesheet], planned: &[&str]) -> Vec<String> {
+    timesheets
         .iter()
-        .map(|sol| Solution {
-            schedules: sol
-                .schedules
+        .flat_map(|ts| ts.excluded.keys().copied())
+        .filter(|subject| !planned.contains(subject))
+        .sorted()
+        .dedup()
+        .map(|subject| {
+            let reasons = timesheets
                 .iter()
-                .map(|sc| Schedule {
-                    table: sc.table.to_owned(),
-                    score: evaluate_solution_semester(sc),
-                    cr_count: sc.cr_count,
-                })
-                .collect_vec(),
-            score: 0f32,
+                .enumerate()
+                .filter_map(|(idx, ts)| ts.excluded.get(subject).map(|r| (idx, r.to_string())));
+            format!(
+                "Left {} out of the plan: {}.",
+                subject,
+                by_semester(reasons)
+            )
         })
-        .collect_vec();
-    for sol in solutions.iter_mut() {
-        sol.score =
-            sol.schedules.iter().map(|sc| sc.score).sum::<f32>() / sol.schedules.len() as f32;
+        .collect()
+}
+
+/// Joins the reasons given for some semesters, by their index in the plan, into e.g.
+/// "not offered in semesters 1, 3; every class overlaps MC102 in semester 2".
+fn by_semester(reasons: impl Iterator<Item = (usize, String)>) -> String {
+    // Reasons in the order first seen, with the semesters each applies to
+    let mut grouped: Vec<(String, Vec<usize>)> = vec![];
+    for (idx, reason) in reasons {
+        match grouped.iter_mut().find(|(r, _)| *r == reason) {
+            Some((_, semesters)) => semesters.push(idx + 1),
+            None => grouped.push((reason, vec![idx + 1])),
+        }
     }
-    solutions
+    grouped
+        .iter()
+        .map(|(reason, semesters)| {
+            format!(
+                "{} in semester{} {}",
+                reason,
+                if semesters.len() > 1 { "s" } else { "" },
+                semesters.iter().join(", ")
+            )
+        })
+        .join("; ")
 }
 
-fn solve_semester<'a>(
+/// Why `sol` does not take `subject`, in each semester of the plan. Semesters past the end of
+/// the solution are taken to be empty.
+fn explain<'a>(
+    subject: &'a str,
+    sol: &SolutionInProgress<'a>,
+    problem: &Problem<'a, '_>,
+) -> String {
+    let empty = HashMap::new();
+    let reasons = problem.timesheets.iter().enumerate().map(|(idx, ts)| {
+        let (table, cr_count) = sol
+            .schedules
+            .get(idx)
+            .map_or((&empty, 0), |sc| (&sc.table, sc.cr_count));
+        let earlier = sol.earlier(idx.min(sol.schedules.len()));
+        let reason = obstacle(subject, ts, table, cr_count, &earlier, idx, problem)
+            .map_or("left out for other subjects".to_string(), |o| o.to_string());
+        (idx, reason)
+    });
+    by_semester(reasons)
+}
+
+/// What keeps `subject` out of the `schedule_idx`-th semester, given the classes in `table`
+/// and the subjects taken before it, if anything.
+fn obstacle<'a>(
+    subject: &str,
     ts: &Timesheet<'a>,
-    solutions: &mut Vec<SolutionInProgress<'a>>,
-    subjects: &Vec<&'a str>,
-    cr_map: &HashMap<&'a str, u8>,
-    cr_max: u8,
+    table: &HashMap<&'a str, Class>,
+    cr_count: u8,
+    earlier: &[&str],
     schedule_idx: usize,
-) {
-    if solutions.is_empty() {
-        let fsub = get_first_subject(ts).unwrap();
-        for c in ts.table.get(fsub).unwrap() {
-            solutions.push(SolutionInProgress {
-                schedules: vec![ScheduleInProgress {
-                    table: HashMap::from([(fsub, c.clone())]),
-                    cr_count: *cr_map.get(fsub).unwrap(),
-                    finished: false,
-                }],
-                satisfied: vec![fsub],
-                goal: subjects.len() as u8,
-            });
-            println!("New solution spawned (1 satisfied)");
-        }
-    } else if solutions[0].schedules.len() == schedule_idx {
-        for sol in solutions.iter_mut() {
-            let schedule = ScheduleInProgress {
-                table: HashMap::new(),
-                cr_count: 0,
-                finished: false,
-            };
-            sol.schedules.push(schedule);
-        }
+    problem: &Problem<'a, '_>,
+) -> Option<Obstacle<'a>> {
+    let classes = ts.table.get(subject).filter(|classes| !classes.is_empty());
+    let Some(classes) = classes else {
+        return Some(Obstacle::NotOffered);
+    };
+    if !problem.allows(subject, schedule_idx) {
+        return Some(Obstacle::OutsideWindow);
     }
-    if solutions.iter().all(|sol| sol.finished()) {
-        println!(
-            "All solutions finished semester {}. {}/{} solved.",
-            schedule_idx + 1,
-            solutions.iter().filter(|sol| sol.solved()).count(),
-            solutions.len()
-        );
-        return;
+    let taking = table.keys().copied().collect::<Vec<_>>();
+    if !problem
+        .prerequisites
+        .get(subject)
+        .is_none_or(|p| p.is_met(&problem.subjects, earlier, &taking))
+    {
+        return Some(Obstacle::Prerequisites);
     }
-    let mut copies = vec![];
-    for sol in solutions.iter_mut().filter(|s| !s.finished()) {
-        let sc = &mut sol.schedules[schedule_idx];
-        if let Some(subject) =
-            get_next_subject(ts, &sol.satisfied, &sc.table, cr_map, sc.cr_count, cr_max)
-        {
-            let classes = ts
-                .table
-                .get(subject)
-                .unwrap()
-                .iter()
-                .filter(|c| c.0.iter().all(|slot| !does_slot_conflict(slot, &sc.table)))
-                .collect::<Vec<_>>();
-            sol.satisfied.push(subject);
-            sc.cr_count += cr_map.get(subject).unwrap();
-            sc.table.insert(subject, classes[0].clone());
-            for c in classes.iter().skip(1) {
-                let mut sol_copy = sol.clone();
-                let sc_copy = &mut sol_copy.schedules[schedule_idx];
-                sc_copy.table.insert(subject, (*c).clone());
-                copies.push(sol_copy);
-            }
-        } else {
-            sc.finished = true;
-        }
-    }
-    for copy in copies {
-        println!("New solution spawned ({} satisfied)", copy.satisfied.len());
-        solutions.push(copy);
-    }
-    solve_semester(ts, solutions, subjects, cr_map, cr_max, schedule_idx);
-}
-
-// pub fn solve_greedy<'a>(
-//     ts1: &Timesheet<'a>,
-//     ts2: &Timesheet<'a>,
-//     cr_map: &HashMap<&'a str, u8>,
-//     cr_max: u8,
-//     ignore: Vec<&'a str>,
-// ) -> SolutionInProgress<'a> {
-//     let mut cr_count = 0;
-//     let ts = ts1.clone();
-//     let mut solution = HashMap::new();
-//     let mut impossible = ignore.clone();
-
-//     while let Some(subject) = get_next_subject(&ts, &solution, &impossible) {
-//         if cr_count >= cr_max {
-//             break;
-//         }
-//         if cr_count + cr_map.get(&subject).unwrap() > cr_max {
-//             impossible.push(subject);
-//         }
-//         if let Some(class) = ts
-//             .table
-//             .get(&subject)
-//             .unwrap()
-//             .iter()
-//             .find(|&c| c.0.iter().all(|slot| !does_slot_conflict(slot, &solution)))
-//         {
-//             solution.insert(subject, class.clone());
-//             cr_count += cr_map.get(&subject).unwrap();
-//         } else {
-//             impossible.push(subject);
-//         }
-//     }
-//     let mut satisfied = solution.clone().into_keys().collect::<Vec<_>>();
-//     satisfied.extend(ignore);
-//     let finished = ts
-//         .table
-//         .keys()
-//         .filter(|&k| !satisfied.contains(k))
-//         .collect::<Vec<_>>()
-//         .is_empty();
-//     if finished {
-//         return SolutionInProgress {
-//             schedules: vec![solution],
-//         };
-//     }
-//     let mut schedules = vec![solution];
-//     schedules.extend(solve_greedy(ts2, ts1, cr_map, cr_max, satisfied).schedules);
-//     SolutionInProgress {
-//         schedules: schedules,
-//     }
-// }
-
-fn get_first_subject<'a>(ts: &Timesheet<'a>) -> Option<&'a str> {
-    let mut min_values = 999;
-    let mut chosen = "";
-    for (subject, classes) in ts.table.iter() {
-        if classes.is_empty() {
-            continue;
-        }
-        if classes.len() < min_values {
-            min_values = classes.len();
-            chosen = subject;
+    let mut subjects = vec![];
+    let mut blocked = false;
+    for class in classes {
+        let overlaps = |slot: &Slot| class.slots.iter().any(|s| s.overlaps(slot));
+        let conflicting = table
+            .iter()
+            .filter(|(_, other)| other.slots.iter().any(overlaps))
+            .map(|(code, _)| *code)
+            .collect::<Vec<_>>();
+        let is_blocked = problem.blocked.iter().any(overlaps);
+        if conflicting.is_empty() && !is_blocked {
+            subjects.clear();
+            blocked = false;
+            break;
         }
+        subjects.extend(conflicting);
+        blocked |= is_blocked;
     }
-    if chosen.is_empty() {
-        return None;
+    if !subjects.is_empty() || blocked {
+        return Some(Obstacle::Conflicts {
+            subjects: subjects.into_iter().sorted().dedup().collect(),
+            blocked,
+        });
     }
-    Some(chosen)
+    let credits = cr_count.saturating_add(*problem.cr_map.get(subject).unwrap_or(&0));
+    let cap = problem.credits.max(schedule_idx);
+    if credits > cap {
+        return Some(Obstacle::Credits { credits, cap });
+    }
+    None
 }
 
 fn get_next_subject<'a>(
     ts: &Timesheet<'a>,
-    satisfied: &[&'a str],
-    current: &HashMap<&'a str, Class>,
-    cr_map: &HashMap<&'a str, u8>,
-    cr_count: u8,
-    cr_max: u8,
+    sol: &SolutionInProgress<'a>,
+    schedule_idx: usize,
+    problem: &Problem<'a, '_>,
 ) -> Option<&'a str> {
+    let satisfied = &sol.satisfied;
+    let current = &sol.schedules[schedule_idx].table;
+    let cr_count = sol.schedules[schedule_idx].cr_count;
+    let earlier = sol.earlier(schedule_idx);
+    let taking = current.keys().copied().collect::<Vec<_>>();
     let mut min_values = 999;
     let mut chosen = "";
     for (subject, classes) in ts.table.iter() {
-        if cr_count + cr_map.get(subject).unwrap() > cr_max {
+        if !problem.allows(subject, schedule_idx) {
+            continue;
+        }
+        if cr_count + problem.cr_map.get(subject).unwrap() > problem.credits.max(schedule_idx) {
+            continue;
+        }
+        if !problem
+            .prerequisites
+            .get(subject)
+            .is_none_or(|p| p.is_met(&problem.subjects, &earlier, &taking))
+        {
             continue;
         }
         let classes_filt = classes
             .iter()
-            .filter(|c| c.0.iter().all(|slot| !does_slot_conflict(slot, current)))
+            .filter(|c| problem.fits(c, current))
             .collect::<Vec<_>>();
         if classes_filt.is_empty() || current.contains_key(*subject) || satisfied.contains(subject)
         {
             continue;
         }
-        if classes_filt.len() < min_values {
+        if sol.declined.contains(subject)
+            || problem
+                .group_of(subject)
+                .is_some_and(|group| problem.is_met(group, satisfied))
+        {
+            continue;
+        }
+        // Fewest classes first, then highest priority, then lowest code so that runs agree
+        if (
+            classes_filt.len(),
+            Reverse(problem.priority(subject)),
+            *subject,
+        ) < (min_values, Reverse(problem.priority(chosen)), chosen)
+        {
             min_values = classes_filt.len();
             chosen = subject;
         }
@@ -271,43 +980,612 @@ fn get_next_subject<'a>(
 
 fn does_slot_conflict(slot: &Slot, current: &HashMap<&str, Class>) -> bool {
     for (_, class) in current.iter() {
-        for slot_other in &class.0 {
-            if slot.weekday == slot_other.weekday
-                && slot.start < slot_other.finish
-                && slot.finish > slot_other.start
-            {
-                return true;
-            }
+        if class
+            .slots
+            .iter()
+            .any(|slot_other| slot.overlaps(slot_other))
+        {
+            return true;
         }
     }
     false
 }
 
-fn evaluate_solution_semester(semester: &ScheduleInProgress) -> f32 {
-    let mut points = vec![];
-    for (_, cl) in semester.table.iter() {
-        for sl in cl.0.iter() {
-            for i in (sl.start / 100)..(sl.finish / 100) {
-                points.push((sl.weekday as f32, i as f32));
-            }
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::unicamp::Exclusion;
+
+    /// A class meeting on `weekday` during `duration`, e.g. "10:00 - 12:00".
+    fn class(weekday: &str, duration: &str) -> Class {
+        Class {
+            slots: vec![Slot::parse(weekday, duration).unwrap()],
+            ..Default::default()
         }
     }
-    let centroid = points.iter().fold((0f32, 0f32), |sum, (x, y)| {
-        (
-            sum.0 + x / points.len() as f32,
-            sum.1 + y / points.len() as f32,
-        )
-    });
-    let score = (points.len() as f32)
-        / points
+
+    /// At most `max` credits in every semester, without blocked windows.
+    fn constraints(max: u8) -> Constraints {
+        Constraints {
+            credits: CreditLimits {
+                max,
+                ..Default::default()
+            },
+            blocked: vec![],
+        }
+    }
+
+    /// Five subjects of 4 credits, each with turmas A to D at the same hours from Monday to
+    /// Thursday, so that most solutions tie.
+    fn tied_timesheet() -> (Timesheet<'static>, HashMap<&'static str, u8>) {
+        let subjects = ["MA111", "MC102", "MC202", "MC322", "MC404"];
+        let weekdays = ["Segunda", "Terça", "Quarta", "Quinta"];
+        let timesheet = Timesheet {
+            table: subjects
+                .iter()
+                .enumerate()
+                .map(|(i, subject)| {
+                    let hours = format!("{:0>2}:00 - {:0>2}:00", 8 + 2 * i, 10 + 2 * i);
+                    let classes = weekdays
+                        .iter()
+                        .enumerate()
+                        .map(|(j, weekday)| Class {
+                            code: ((b'A' + j as u8) as char).to_string(),
+                            ..class(weekday, &hours)
+                        })
+                        .collect();
+                    (*subject, classes)
+                })
+                .collect(),
+            excluded: HashMap::new(),
+        };
+        let cr_map = subjects.iter().map(|subject| (*subject, 4)).collect();
+        (timesheet, cr_map)
+    }
+
+    #[test]
+    fn reports_subjects_without_classes() {
+        let timesheet = |excluded: Vec<(&'static str, Exclusion)>| Timesheet {
+            table: HashMap::from([("MC102", vec![Class::default()])]),
+            excluded: excluded.into_iter().collect(),
+        };
+        let timesheets = [
+            timesheet(vec![
+                ("MC202", Exclusion::NotOffered),
+                ("MC322", Exclusion::NotOffered),
+            ]),
+            timesheet(vec![(
+                "MC202",
+                Exclusion::Unknown(Some("HTTP 503".to_string())),
+            )]),
+            timesheet(vec![("MC202", Exclusion::NotOffered)]),
+        ];
+        let mut with_mc322 = timesheets.clone();
+        with_mc322[1].table.insert("MC322", vec![Class::default()]);
+        assert_eq!(
+            exclusion_report(&with_mc322, &["MC102", "MC322"]),
+            [
+                "Left MC202 out of the plan: not offered in semesters 1, 3; could not be fetched \
+              (HTTP 503) in semester 2."
+            ]
+        );
+        assert_eq!(exclusion_report(&timesheets, &["MC102"]).len(), 2);
+    }
+
+    #[test]
+    fn takes_only_enough_electives() {
+        let timesheet = Timesheet {
+            table: ["MC102", "MC855", "MC859", "MC886"]
+                .into_iter()
+                .map(|subject| (subject, vec![Class::default()]))
+                .collect(),
+            excluded: HashMap::new(),
+        };
+        let cr_map = HashMap::from([("MC102", 6), ("MC855", 4), ("MC859", 4), ("MC886", 2)]);
+        let solve = |min_count, min_credits| {
+            let goals = Goals {
+                subjects: HashMap::new(),
+                electives: vec![ElectiveGroup {
+                    name: "Electives".to_string(),
+                    subjects: vec!["MC855", "MC859", "MC886"],
+                    min_count,
+                    min_credits,
+                }],
+            };
+            let constraints = constraints(24);
+            solve_all(
+                std::slice::from_ref(&timesheet),
+                &goals,
+                &cr_map,
+                &HashMap::new(),
+                &constraints,
+                &Scoring::default(),
+                &SearchLimits {
+                    keep: 10,
+                    ..Default::default()
+                },
+            )
+        };
+
+        let solutions = solve(1, 0);
+        assert_eq!(solutions.len(), 3);
+        for solution in solutions.iter() {
+            assert!(solution.schedules[0].table.contains_key("MC102"));
+            assert_eq!(solution.electives[0].1.len(), 1);
+        }
+        let taken = solutions
+            .iter()
+            .map(|solution| solution.electives[0].1[0])
+            .sorted()
+            .collect::<Vec<_>>();
+        assert_eq!(taken, ["MC855", "MC859", "MC886"]);
+
+        // MC886 alone is not enough credits
+        let solutions = solve(1, 4);
+        assert!(!solutions.is_empty());
+        for solution in solutions.iter() {
+            assert_ne!(solution.electives[0].1, ["MC886"]);
+        }
+        assert!(solve(3, 0)
+            .iter()
+            .all(|solution| solution.electives[0].1.len() == 3));
+    }
+
+    #[test]
+    fn keeps_solutions_taking_the_most_priority() {
+        let timesheet = |table| Timesheet {
+            table,
+            excluded: HashMap::new(),
+        };
+        let first = timesheet(HashMap::from([
+            ("MC102", vec![class("Segunda", "10:00 - 12:00")]),
+            ("MC202", vec![class("Segunda", "10:00 - 12:00")]),
+            ("MC322", vec![class("Terça", "10:00 - 12:00")]),
+            ("MC886", vec![class("Quinta", "10:00 - 12:00")]),
+            ("MC999", vec![class("Sexta", "14:00 - 16:00")]),
+        ]));
+        let goal = |priority, optional| SubjectGoal {
+            priority,
+            optional,
+            semesters: 0..=usize::MAX,
+        };
+        let goals = Goals {
+            subjects: HashMap::from([("MC202", goal(3, false)), ("MC322", goal(1, true))]),
+            electives: vec![],
+        };
+        let cr_map = HashMap::from([
+            ("MC102", 4),
+            ("MC202", 4),
+            ("MC322", 4),
+            ("MC886", 30),
+            ("MC999", 4),
+        ]);
+        let constraints = Constraints {
+            blocked: vec![Slot::parse("Sexta", "12:00 - 18:00").unwrap()],
+            ..constraints(24)
+        };
+        let solutions = solve_all(
+            &[first, timesheet(HashMap::new()), timesheet(HashMap::new())],
+            &goals,
+            &cr_map,
+            &HashMap::new(),
+            &constraints,
+            &Scoring::default(),
+            &SearchLimits {
+                keep: 10,
+                ..Default::default()
+            },
+        );
+        // MC102 and MC202 never fit together and MC886 and MC999 never fit, so no solution is solved and
+        // the search stops after the first semester
+        assert!(solutions.iter().all(|sol| sol.schedules.len() == 1));
+        let best = &solutions[0];
+        assert_eq!(best.schedules[0].table.len(), 2);
+        assert_eq!(
+            best.missing,
+            [
+                (
+                    "MC102",
+                    "every class overlaps MC202 in semester 1; not offered in semesters 2, 3"
+                        .to_string()
+                ),
+                (
+                    "MC886",
+                    "would take 38 credits, over the cap of 24 in semester 1; not offered in \
+                     semesters 2, 3"
+                        .to_string()
+                ),
+                (
+                    "MC999",
+                    "every class overlaps a blocked window in semester 1; not offered in \
+                     semesters 2, 3"
+                        .to_string()
+                )
+            ]
+        );
+        let codes =
+            |sol: &Solution<'static>| sol.missing.iter().map(|(s, _)| *s).collect::<Vec<_>>();
+        let declined = solutions
+            .iter()
+            .find(|sol| codes(sol) == ["MC102", "MC322", "MC886", "MC999"])
+            .unwrap();
+        assert_eq!(
+            declined.missing[1].1,
+            "left out for other subjects in semester 1; not offered in semesters 2, 3"
+        );
+    }
+
+    /// Why the best solution leaves out `subject`, out of a plan in which MC102 is the only
+    /// subject that can be taken, each of the others for a different reason.
+    fn left_out(subject: &str) -> String {
+        let first = Timesheet {
+            table: HashMap::from([
+                ("MC102", vec![class("Segunda", "10:00 - 12:00")]),
+                ("MC202", vec![class("Terça", "10:00 - 12:00")]),
+                ("MC322", vec![class("Segunda", "10:00 - 12:00")]),
+                ("MC404", vec![class("Sexta", "14:00 - 16:00")]),
+                ("MC886", vec![class("Quinta", "10:00 - 12:00")]),
+            ]),
+            excluded: HashMap::new(),
+        };
+        let second = Timesheet {
+            table: HashMap::from([("MC999", vec![class("Quarta", "10:00 - 12:00")])]),
+            excluded: HashMap::new(),
+        };
+        let goals = Goals {
+            subjects: HashMap::from([(
+                "MC999",
+                SubjectGoal {
+                    priority: 1,
+                    optional: false,
+                    semesters: 0..=0,
+                },
+            )]),
+            electives: vec![],
+        };
+        let cr_map = HashMap::from([
+            ("MC102", 4),
+            ("MC202", 4),
+            ("MC322", 4),
+            ("MC404", 4),
+            ("MC886", 30),
+            ("MC999", 4),
+        ]);
+        let prerequisites = HashMap::from([("MC202", Prerequisites::from("MC102"))]);
+        let constraints = Constraints {
+            blocked: vec![Slot::parse("Sexta", "12:00 - 18:00").unwrap()],
+            ..constraints(24)
+        };
+        let solutions = solve_all(
+            &[first, second],
+            &goals,
+            &cr_map,
+            &prerequisites,
+            &constraints,
+            &Scoring::default(),
+            &SearchLimits::default(),
+        );
+        let best = &solutions[0];
+        assert_eq!(best.schedules[0].table.keys().collect_vec(), [&"MC102"]);
+        let (_, reason) = best.missing.iter().find(|(s, _)| *s == subject).unwrap();
+        reason.clone()
+    }
+
+    #[test]
+    fn explains_subjects_not_offered_and_outside_their_semesters() {
+        assert_eq!(
+            left_out("MC999"),
+            "not offered in semester 1; not allowed by the plan in semester 2"
+        );
+    }
+
+    #[test]
+    fn explains_unmet_prerequisites() {
+        assert_eq!(
+            left_out("MC202"),
+            "prerequisites not met in semester 1; not offered in semester 2"
+        );
+    }
+
+    #[test]
+    fn explains_time_clashes() {
+        assert_eq!(
+            left_out("MC322"),
+            "every class overlaps MC102 in semester 1; not offered in semester 2"
+        );
+    }
+
+    #[test]
+    fn explains_blocked_windows() {
+        assert_eq!(
+            left_out("MC404"),
+            "every class overlaps a blocked window in semester 1; not offered in semester 2"
+        );
+    }
+
+    #[test]
+    fn explains_credit_caps() {
+        assert_eq!(
+            left_out("MC886"),
+            "would take 34 credits, over the cap of 24 in semester 1; not offered in semester 2"
+        );
+    }
+
+    #[test]
+    fn avoids_blocked_windows() {
+        let class = |code: &str, weekday| Class {
+            code: code.to_string(),
+            ..class(weekday, "14:00 - 16:00")
+        };
+        let timesheet = Timesheet {
+            table: HashMap::from([(
+                "MC102",
+                vec![
+                    class("A", "Segunda"),
+                    class("B", "Terça"),
+                    class("C", "Quinta"),
+                ],
+            )]),
+            excluded: HashMap::new(),
+        };
+        let constraints = Constraints {
+            blocked: vec![
+                Slot::parse("Segunda", "15:00 - 17:00").unwrap(),
+                Slot::parse("Quinta", "08:00 - 14:30").unwrap(),
+            ],
+            ..constraints(24)
+        };
+        let solutions = solve_all(
+            &[timesheet],
+            &Goals::default(),
+            &HashMap::from([("MC102", 4)]),
+            &HashMap::new(),
+            &constraints,
+            &Scoring::default(),
+            &SearchLimits::default(),
+        );
+        let turmas = solutions
             .iter()
-            .fold(0f32, |sum, (x, y)| {
-                sum + ((centroid.0 - x).powf(2f32) + (centroid.1 - y).powf(2f32)).sqrt()
-                    / points.len() as f32
+            .map(|sol| sol.schedules[0].table["MC102"].code.as_str())
+            .collect_vec();
+        assert_eq!(turmas, ["B"]);
+    }
+
+    #[test]
+    fn keeps_credits_within_the_limits() {
+        let subjects = [
+            ("MC102", "Segunda"),
+            ("MC202", "Terça"),
+            ("MC322", "Quarta"),
+        ];
+        let table = subjects
+            .iter()
+            .map(|(subject, weekday)| (*subject, vec![class(weekday, "10:00 - 12:00")]))
+            .collect::<HashMap<_, _>>();
+        let timesheet = Timesheet {
+            table,
+            excluded: HashMap::new(),
+        };
+        let cr_map = HashMap::from([("MC102", 6), ("MC202", 4), ("MC322", 4)]);
+        let solve = |min, caps| {
+            let constraints = Constraints {
+                credits: CreditLimits { min, max: 24, caps },
+                ..constraints(24)
+            };
+            solve_all(
+                &[timesheet.clone(), timesheet.clone()],
+                &Goals::default(),
+                &cr_map,
+                &HashMap::new(),
+                &constraints,
+                &Scoring::default(),
+                &SearchLimits::default(),
+            )
+        };
+        let credits = |sol: &Solution| sol.schedules.iter().map(|sc| sc.cr_count).collect_vec();
+
+        let solutions = solve(0, HashMap::new());
+        assert!(!solutions.is_empty());
+        assert!(solutions.iter().all(|sol| credits(sol) == [14]));
+        // MC102 is taken first and leaves no room for the others under the cap
+        let solutions = solve(0, HashMap::from([(0, 8)]));
+        assert!(!solutions.is_empty());
+        assert!(solutions.iter().all(|sol| credits(sol) == [6, 8]));
+        // The semester that completes the plan may stay under the minimum
+        let solutions = solve(6, HashMap::from([(0, 10)]));
+        assert!(!solutions.is_empty());
+        assert!(solutions.iter().all(|sol| credits(sol) == [10, 4]));
+        // A first semester of 6 credits is under the minimum, so every branch is dropped
+        assert!(solve(8, HashMap::from([(0, 8)])).is_empty());
+    }
+
+    #[test]
+    fn fills_each_semester_before_the_next() {
+        let table = [("MC102", "Segunda"), ("MC202", "Terça")]
+            .iter()
+            .map(|(subject, weekday)| (*subject, vec![class(weekday, "10:00 - 12:00")]))
+            .collect::<HashMap<_, _>>();
+        let timesheet = Timesheet {
+            table,
+            excluded: HashMap::new(),
+        };
+        let scoring = Scoring::new(&[
+            ("compactness".to_string(), 0f32),
+            ("free-days".to_string(), 1f32),
+        ])
+        .unwrap();
+        let solutions = solve_all(
+            &[timesheet.clone(), timesheet],
+            &Goals::default(),
+            &HashMap::from([("MC102", 4), ("MC202", 4)]),
+            &HashMap::new(),
+            &constraints(24),
+            &scoring,
+            &SearchLimits::default(),
+        );
+        // Taking one subject per semester would leave more free days, but neither is postponed
+        assert!(!solutions.is_empty());
+        assert!(solutions.iter().all(|sol| sol.schedules.len() == 1));
+    }
+
+    #[test]
+    fn keeps_the_best_solutions() {
+        let timesheet = Timesheet {
+            table: HashMap::from([
+                (
+                    "MC102",
+                    vec![
+                        class("Segunda", "08:00 - 10:00"),
+                        class("Terça", "08:00 - 10:00"),
+                    ],
+                ),
+                (
+                    "MC202",
+                    vec![
+                        class("Segunda", "10:00 - 12:00"),
+                        class("Quarta", "10:00 - 12:00"),
+                    ],
+                ),
+                (
+                    "MC322",
+                    vec![
+                        class("Segunda", "14:00 - 16:00"),
+                        class("Quinta", "14:00 - 16:00"),
+                    ],
+                ),
+            ]),
+            excluded: HashMap::new(),
+        };
+        let cr_map = HashMap::from([("MC102", 4), ("MC202", 4), ("MC322", 4)]);
+        let constraints = constraints(24);
+        let scoring = Scoring::new(&[
+            ("compactness".to_string(), 0f32),
+            ("free-days".to_string(), 1f32),
+        ])
+        .unwrap();
+        let solve = |keep| {
+            solve_all(
+                std::slice::from_ref(&timesheet),
+                &Goals::default(),
+                &cr_map,
+                &HashMap::new(),
+                &constraints,
+                &scoring,
+                &SearchLimits {
+                    keep,
+                    ..Default::default()
+                },
+            )
+        };
+        let all = solve(100);
+        assert_eq!(all.len(), 8);
+        let best = solve(3);
+        assert_eq!(best.len(), 3);
+        assert_eq!(
+            best.iter().map(|sol| sol.score).collect::<Vec<_>>(),
+            all.iter().take(3).map(|sol| sol.score).collect::<Vec<_>>()
+        );
+        assert_eq!(best[0].score, 4f32);
+        assert!(best[0].schedules[0]
+            .table
+            .values()
+            .all(|class| class.slots[0].weekday == 2));
+    }
+
+    #[test]
+    fn returns_the_same_solutions_every_run() {
+        let (timesheet, cr_map) = tied_timesheet();
+        let constraints = constraints(24);
+        let scoring = Scoring::new(&[("free-days".to_string(), 1f32)]).unwrap();
+        let solve = |keep| {
+            solve_all(
+                std::slice::from_ref(&timesheet),
+                &Goals::default(),
+                &cr_map,
+                &HashMap::new(),
+                &constraints,
+                &scoring,
+                &SearchLimits {
+                    keep,
+                    ..Default::default()
+                },
+            )
+            .into_iter()
+            .map(|sol| {
+                let classes = sol.schedules[0]
+                    .table
+                    .iter()
+                    .map(|(subject, class)| (*subject, class.code.clone()))
+                    .sorted()
+                    .collect::<Vec<_>>();
+                (sol.score, classes)
             })
-            .powf(2f32);
-    if score.is_finite() {
-        return score;
+            .collect::<Vec<_>>()
+        };
+        let first = solve(5);
+        assert_eq!(first.len(), 5);
+        for _ in 0..5 {
+            assert_eq!(solve(5), first);
+        }
+        assert_eq!(solve(usize::MAX)[..5], first);
+    }
+
+    #[test]
+    fn breaks_ties_between_classes_without_turma() {
+        let at_ten = |weekday| class(weekday, "10:00 - 12:00");
+        let solve = |classes| {
+            let timesheet = Timesheet {
+                table: HashMap::from([("MC102", classes)]),
+                excluded: HashMap::new(),
+            };
+            let solutions = solve_all(
+                &[timesheet],
+                &Goals::default(),
+                &HashMap::from([("MC102", 4)]),
+                &HashMap::new(),
+                &constraints(24),
+                &Scoring::default(),
+                &SearchLimits {
+                    keep: 1,
+                    ..Default::default()
+                },
+            );
+            solutions[0].schedules[0].table["MC102"].slots[0].weekday
+        };
+        // Both classes score the same and only differ in slots, whichever is found first
+        assert_eq!(solve(vec![at_ten("Terça"), at_ten("Segunda")]), 2);
+        assert_eq!(solve(vec![at_ten("Segunda"), at_ten("Terça")]), 2);
+    }
+
+    #[test]
+    fn stops_at_the_solution_limit() {
+        let (timesheet, cr_map) = tied_timesheet();
+        let constraints = constraints(24);
+        let solve = |max_solutions| {
+            solve_all(
+                std::slice::from_ref(&timesheet),
+                &Goals::default(),
+                &cr_map,
+                &HashMap::new(),
+                &constraints,
+                &Scoring::new(&[]).unwrap(),
+                &SearchLimits {
+                    keep: usize::MAX,
+                    max_solutions,
+                    ..Default::default()
+                },
+            )
+        };
+
+        let all = solve(None);
+        assert_eq!(all.len(), 4usize.pow(5));
+        assert_eq!(all[0].search.stopped, None);
+        let some = solve(Some(3));
+        // Workers already searching may each find one more before they see the limit
+        assert!(some.len() >= 3 && some.len() < all.len());
+        for sol in some.iter() {
+            assert_eq!(sol.search.stopped, Some(Stop::MaxSolutions));
+            assert_eq!(sol.search.found, some.len());
+        }
     }
-    0f32
 }
@@ -0,0 +1,636 @@
+use rusqlite::{params, Connection, OptionalExtension, Transaction};
+use std::path::{Path, PathBuf};
+use time::format_description::well_known::Rfc3339;
+use time::OffsetDateTime;
+
+use crate::cache::{CacheContents, CacheError, ClassesEntry, Offering, Store};
+use crate::unicamp::{Class, Prerequisites, Semester, Slot, Subject};
+
+/// Version of the database schema, kept in `PRAGMA user_version`.
+const SCHEMA_VERSION: i64 = 2;
+
+const SCHEMA: &str = "
+CREATE TABLE IF NOT EXISTS subjects (
+    code TEXT PRIMARY KEY,
+    institute TEXT,
+    credits INTEGER,
+    -- JSON alternatives of requirements, NULL until scraped
+    prerequisites TEXT
+);
+CREATE TABLE IF NOT EXISTS offerings (
+    id INTEGER PRIMARY KEY,
+    subject TEXT NOT NULL REFERENCES subjects(code),
+    semester TEXT NOT NULL,
+    year INTEGER NOT NULL,
+    term INTEGER NOT NULL,
+    scraped_at TEXT,
+    source TEXT,
+    -- offered, not-offered or unknown
+    offering TEXT NOT NULL DEFAULT 'offered',
+    -- why the page could not be fetched, when unknown
+    error TEXT,
+    UNIQUE (subject, semester)
+);
+CREATE TABLE IF NOT EXISTS classes (
+    id INTEGER PRIMARY KEY,
+    offering INTEGER NOT NULL REFERENCES offerings(id) ON DELETE CASCADE,
+    position INTEGER NOT NULL,
+    turma TEXT NOT NULL,
+    vacancies INTEGER,
+    reserved INTEGER
+);
+CREATE TABLE IF NOT EXISTS professors (
+    class INTEGER NOT NULL REFERENCES classes(id) ON DELETE CASCADE,
+    position INTEGER NOT NULL,
+    name TEXT NOT NULL
+);
+CREATE TABLE IF NOT EXISTS slots (
+    class INTEGER NOT NULL REFERENCES classes(id) ON DELETE CASCADE,
+    weekday INTEGER NOT NULL,
+    start INTEGER NOT NULL,
+    finish INTEGER NOT NULL,
+    room TEXT
+);
+";
+
+/// Upgrades a database of version 1, whose offerings did not tell an outage from a subject not
+/// offered.
+const MIGRATION_1: &str = "
+ALTER TABLE offerings ADD COLUMN offering TEXT NOT NULL DEFAULT 'offered';
+ALTER TABLE offerings ADD COLUMN error TEXT;
+UPDATE offerings SET offering = 'unknown' WHERE id NOT IN (SELECT offering FROM classes);
+";
+
+/// Caches everything in a single SQLite database, one row per subject, offering (subject in
+/// a semester), class, professor and slot, so that past offerings can be queried with SQL.
+pub struct SqliteStore {
+    path: PathBuf,
+    conn: Connection,
+}
+
+impl SqliteStore {
+    /// Opens the database at `path`, creating it if needed.
+    pub fn open(path: &Path) -> Result<Self, CacheError> {
+        let conn = Connection::open(path).map_err(|e| read_error(path, e))?;
+        let store = SqliteStore {
+            path: path.to_path_buf(),
+            conn,
+        };
+        let version: i64 = store
+            .conn
+            .query_row("PRAGMA user_version", [], |row| row.get(0))
+            .map_err(|e| store.corrupt(e))?;
+        if version > SCHEMA_VERSION {
+            return Err(store.corrupt(format!(
+                "version {} is newer than the supported {}",
+                version, SCHEMA_VERSION
+            )));
+        }
+        store
+            .conn
+            .execute_batch(&format!(
+                "PRAGMA foreign_keys = ON;{}{}PRAGMA user_version = {};",
+                if version == 1 { MIGRATION_1 } else { "" },
+                SCHEMA,
+                SCHEMA_VERSION
+            ))
+            .map_err(|e| store.write_error(e))?;
+        Ok(store)
+    }
+
+    fn read_error(&self, e: impl ToString) -> CacheError {
+        read_error(&self.path, e)
+    }
+
+    fn write_error(&self, e: impl ToString) -> CacheError {
+        CacheError::Write {
+            path: self.path.clone(),
+            reason: e.to_string(),
+        }
+    }
+
+    fn corrupt(&self, e: impl ToString) -> CacheError {
+        CacheError::Corrupt {
+            path: self.path.clone(),
+            reason: e.to_string(),
+        }
+    }
+}
+
+fn read_error(path: &Path, e: impl ToString) -> CacheError {
+    CacheError::Read {
+        path: path.to_path_buf(),
+        reason: e.to_string(),
+    }
+}
+
+fn upsert_subject(tx: &Transaction, code: &str, institute: Option<&str>) -> rusqlite::Result<()> {
+    tx.execute(
+        "INSERT INTO subjects (code, institute) VALUES (?1, ?2)
+         ON CONFLICT (code) DO UPDATE SET institute = COALESCE(?2, institute)",
+        params![code, institute],
+    )?;
+    Ok(())
+}
+
+/// Replaces the offering of `code` in `semester` with `entry`.
+fn insert_offering(
+    tx: &Transaction,
+    code: &str,
+    institute: Option<&str>,
+    semester: &Semester,
+    entry: &ClassesEntry,
+) -> rusqlite::Result<()> {
+    upsert_subject(tx, code, institute)?;
+    tx.execute(
+        "DELETE FROM offerings WHERE subject = ?1 AND semester = ?2",
+        params![code, semester.to_string()],
+    )?;
+    tx.execute(
+        "INSERT INTO offerings
+         (subject, semester, year, term, scraped_at, source, offering, error)
+         VALUES (?1, ?2, ?3, ?4, ?5, ?6, ?7, ?8)",
+        params![
+            code,
+            semester.to_string(),
+            semester.year,
+            semester.semester,
+            entry.scraped_at.map(|t| t.format(&Rfc3339).unwrap()),
+            entry.source,
+            entry.offering.to_string(),
+            entry.error,
+        ],
+    )?;
+    let offering = tx.last_insert_rowid();
+    for (position, class) in entry.classes.iter().enumerate() {
+        tx.execute(
+            "INSERT INTO classes (offering, position, turma, vacancies, reserved)
+             VALUES (?1, ?2, ?3, ?4, ?5)",
+            params![
+                offering,
+                position as i64,
+                class.code,
+                class.vacancies,
+                class.reserved
+            ],
+        )?;
+        let id = tx.last_insert_rowid();
+        for (position, name) in class.professors.iter().enumerate() {
+            tx.execute(
+                "INSERT INTO professors (class, position, name) VALUES (?1, ?2, ?3)",
+                params![id, position as i64, name],
+            )?;
+        }
+        for slot in class.slots.iter() {
+            tx.execute(
+                "INSERT INTO slots (class, weekday, start, finish, room) VALUES (?1, ?2, ?3, ?4, ?5)",
+                params![id, slot.weekday, slot.start, slot.finish, slot.room],
+            )?;
+        }
+    }
+    Ok(())
+}
+
+impl SqliteStore {
+    /// Reads the classes of subject `code` in `semester`.
+    fn load_entry(&self, code: &str, semester: &str) -> Result<Option<ClassesEntry>, CacheError> {
+        let offering = self
+            .conn
+            .query_row(
+                "SELECT id, scraped_at, source, offering, error FROM offerings
+                 WHERE subject = ?1 AND semester = ?2",
+                params![code, semester],
+                |row| {
+                    Ok((
+                        row.get::<_, i64>(0)?,
+                        row.get::<_, Option<String>>(1)?,
+                        row.get::<_, Option<String>>(2)?,
+                        row.get::<_, String>(3)?,
+                        row.get::<_, Option<String>>(4)?,
+                    ))
+                },
+            )
+            .optional()
+            .map_err(|e| self.read_error(e))?;
+        let Some((id, scraped_at, source, offering, error)) = offering else {
+            return Ok(None);
+        };
+        let offering = Offering::from_name(&offering)
+            .ok_or_else(|| self.corrupt(format!("invalid offering {:?}", offering)))?;
+        let scraped_at = match scraped_at {
+            Some(t) => Some(OffsetDateTime::parse(&t, &Rfc3339).map_err(|e| self.corrupt(e))?),
+            None => None,
+        };
+        let mut classes_query = self
+            .conn
+            .prepare(
+                "SELECT id, turma, vacancies, reserved FROM classes
+                 WHERE offering = ?1 ORDER BY position",
+            )
+            .map_err(|e| self.read_error(e))?;
+        let mut professors_query = self
+            .conn
+            .prepare("SELECT name FROM professors WHERE class = ?1 ORDER BY position")
+            .map_err(|e| self.read_error(e))?;
+        let mut slots_query = self
+            .conn
+            .prepare(
+                "SELECT weekday, start, finish, room FROM slots WHERE class = ?1 ORDER BY rowid",
+            )
+            .map_err(|e| self.read_error(e))?;
+        let rows = classes_query
+            .query_map(params![id], |row| {
+                Ok((
+                    row.get::<_, i64>(0)?,
+                    Class {
+                        code: row.get(1)?,
+                        vacancies: row.get(2)?,
+                        reserved: row.get(3)?,
+                        ..Default::default()
+                    },
+                ))
+            })
+            .map_err(|e| self.read_error(e))?
+            .collect::<Result<Vec<_>, _>>()
+            .map_err(|e| self.read_error(e))?;
+        let mut classes = vec![];
+        for (id, mut class) in rows {
+            class.professors = professors_query
+                .query_map(params![id], |row| row.get(0))
+                .map_err(|e| self.read_error(e))?
+                .collect::<Result<_, _>>()
+                .map_err(|e| self.read_error(e))?;
+            class.slots = slots_query
+                .query_map(params![id], |row| {
+                    Ok(Slot {
+                        weekday: row.get(0)?,
+                        start: row.get(1)?,
+                        finish: row.get(2)?,
+                        room: row.get(3)?,
+                    })
+                })
+                .map_err(|e| self.read_error(e))?
+                .collect::<Result<_, _>>()
+                .map_err(|e| self.read_error(e))?;
+            classes.push(class);
+        }
+        Ok(Some(ClassesEntry {
+            scraped_at,
+            source,
+            offering,
+            error,
+            classes,
+        }))
+    }
+}
+
+impl Store for SqliteStore {
+    fn load_classes(
+        &self,
+        subject: &Subject,
+        semester: &Semester,
+    ) -> Result<Option<ClassesEntry>, CacheError> {
+        self.load_entry(subject.code, &semester.to_string())
+    }
+
+    fn load_credits(&self, subject: &Subject) -> Result<Option<u8>, CacheError> {
+        self.conn
+            .query_row(
+                "SELECT credits FROM subjects WHERE code = ?1",
+                params![subject.code],
+                |row| row.get(0),
+            )
+            .optional()
+            .map(Option::flatten)
+            .map_err(|e| self.read_error(e))
+    }
+
+    fn load_prerequisites(&self, subject: &Subject) -> Result<Option<Prerequisites>, CacheError> {
+        let json: Option<String> = self
+            .conn
+            .query_row(
+                "SELECT prerequisites FROM subjects WHERE code = ?1",
+                params![subject.code],
+                |row| row.get(0),
+            )
+            .optional()
+            .map(Option::flatten)
+            .map_err(|e| self.read_error(e))?;
+        json.map(|json| serde_json::from_str(&json).map_err(|e| self.corrupt(e)))
+            .transpose()
+    }
+
+    fn save_classes(
+        &self,
+        subject: &Subject,
+        semester: &Semester,
+        entry: &ClassesEntry,
+    ) -> Result<(), CacheError> {
+        let tx = self
+            .conn
+            .unchecked_transaction()
+            .map_err(|e| self.write_error(e))?;
+        insert_offering(&tx, subject.code, Some(subject.institute), semester, entry)
+            .map_err(|e| self.write_error(e))?;
+        tx.commit().map_err(|e| self.write_error(e))
+    }
+
+    fn save_credits(&self, subject: &Subject, credits: u8) -> Result<(), CacheError> {
+        self.conn
+            .execute(
+                "INSERT INTO subjects (code, institute, credits) VALUES (?1, ?2, ?3)
+                 ON CONFLICT (code) DO UPDATE SET institute = ?2, credits = ?3",
+                params![subject.code, subject.institute, credits],
+            )
+            .map(|_| ())
+            .map_err(|e| self.write_error(e))
+    }
+
+    fn save_prerequisites(
+        &self,
+        subject: &Subject,
+        prerequisites: &Prerequisites,
+    ) -> Result<(), CacheError> {
+        self.conn
+            .execute(
+                "INSERT INTO subjects (code, institute, prerequisites) VALUES (?1, ?2, ?3)
+                 ON CONFLICT (code) DO UPDATE SET institute = ?2, prerequisites = ?3",
+                params![
+                    subject.code,
+                    subject.institute,
+                    serde_json::to_string(prerequisites).unwrap()
+                ],
+            )
+            .map(|_| ())
+            .map_err(|e| self.write_error(e))
+    }
+
+    fn contents(&self) -> Result<CacheContents, CacheError> {
+        let mut contents = CacheContents::default();
+        let offerings = self
+            .conn
+            .prepare("SELECT subject, semester FROM offerings")
+            .and_then(|mut query| {
+                query
+                    .query_map([], |row| {
+                        Ok((row.get::<_, String>(0)?, row.get::<_, String>(1)?))
+                    })?
+                    .collect::<Result<Vec<_>, _>>()
+            })
+            .map_err(|e| self.read_error(e))?;
+        for (code, semester) in offerings {
+            if let Some(entry) = self.load_entry(&code, &semester)? {
+                contents
+                    .classes
+                    .entry(code)
+                    .or_default()
+                    .insert(semester, entry);
+            }
+        }
+        let subjects = self
+            .conn
+            .prepare("SELECT code, credits, prerequisites FROM subjects")
+            .and_then(|mut query| {
+                query
+                    .query_map([], |row| {
+                        Ok((
+                            row.get::<_, String>(0)?,
+                            row.get::<_, Option<u8>>(1)?,
+                            row.get::<_, Option<String>>(2)?,
+                        ))
+                    })?
+                    .collect::<Result<Vec<_>, _>>()
+            })
+            .map_err(|e| self.read_error(e))?;
+        for (code, credits, prerequisites) in subjects {
+            if let Some(credits) = credits {
+                contents.credits.insert(code.clone(), credits);
+            }
+            if let Some(json) = prerequisites {
+                contents.prerequisites.insert(
+                    code,
+                    serde_json::from_str(&json).map_err(|e| self.corrupt(e))?,
+                );
+            }
+        }
+        Ok(contents)
+    }
+
+    fn import(&self, contents: &CacheContents) -> Result<(), CacheError> {
+        let tx = self
+            .conn
+            .unchecked_transaction()
+            .map_err(|e| self.write_error(e))?;
+        for (code, entries) in contents.classes.iter() {
+            for (semester, entry) in entries.iter() {
+                let semester = Semester::parse(semester).ok_or_else(|| {
+                    self.write_error(format!("invalid semester {:?} of {}", semester, code))
+                })?;
+                insert_offering(&tx, code, None, &semester, entry)
+                    .map_err(|e| self.write_error(e))?;
+            }
+        }
+        for (code, credits) in contents.credits.iter() {
+            upsert_subject(&tx, code, None).map_err(|e| self.write_error(e))?;
+            tx.execute(
+                "UPDATE subjects SET credits = ?2 WHERE code = ?1",
+                params![code, credits],
+            )
+            .map_err(|e| self.write_error(e))?;
+        }
+        for (code, prerequisites) in contents.prerequisites.iter() {
+            upsert_subject(&tx, code, None).map_err(|e| self.write_error(e))?;
+            tx.execute(
+                "UPDATE subjects SET prerequisites = ?2 WHERE code = ?1",
+                params![code, serde_json::to_string(prerequisites).unwrap()],
+            )
+            .map_err(|e| self.write_error(e))?;
+        }
+        tx.commit().map_err(|e| self.write_error(e))?;
+        Ok(())
+    }
+
+    fn remove(
+        &self,
+        subject: Option<&str>,
+        semester: Option<&Semester>,
+    ) -> Result<usize, CacheError> {
+        let semester = semester.map(|semester| semester.to_string());
+        let tx = self
+            .conn
+            .unchecked_transaction()
+            .map_err(|e| self.write_error(e))?;
+        let removed = tx
+            .execute(
+                "DELETE FROM offerings WHERE (?1 IS NULL OR subject = ?1)
+                 AND (?2 IS NULL OR semester = ?2)",
+                params![subject, semester],
+            )
+            .map_err(|e| self.write_error(e))?;
+        if semester.is_none() {
+            tx.execute(
+                "DELETE FROM subjects WHERE ?1 IS NULL OR code = ?1",
+                params![subject],
+            )
+            .map_err(|e| self.write_error(e))?;
+        }
+        tx.commit().map_err(|e| self.write_error(e))?;
+        Ok(removed)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::unicamp::Requirement;
+    use std::collections::HashMap;
+
+    const MC202: Subject = Subject::new("IC", "MC202");
+
+    fn classes() -> Vec<Class> {
+        vec![
+            Class {
+                code: "A".to_string(),
+                professors: vec!["Ana Souza".to_string(), "Bruno Lima".to_string()],
+                vacancies: Some(60),
+                reserved: None,
+                slots: vec![
+                    Slot {
+                        room: Some("CB01".to_string()),
+                        ..Slot::parse("Segunda", "10:00 - 12:00").unwrap()
+                    },
+                    Slot::parse("Quarta", "10:00 - 12:00").unwrap(),
+                ],
+            },
+            Class {
+                code: "B".to_string(),
+                slots: vec![Slot::parse("Sexta", "19:00 - 23:00").unwrap()],
+                ..Default::default()
+            },
+        ]
+    }
+
+    #[test]
+    fn round_trips_entries() {
+        let store = SqliteStore::open(Path::new(":memory:")).unwrap();
+        let semester = Semester::from("1s2024");
+        let prerequisites = Prerequisites(vec![vec![Requirement::Subject {
+            code: "MC102".to_string(),
+            partial: false,
+        }]]);
+        assert_eq!(store.load_classes(&MC202, &semester), Ok(None));
+        assert_eq!(store.load_credits(&MC202), Ok(None));
+        store
+            .save_classes(
+                &MC202,
+                &semester,
+                &ClassesEntry::scraped("here", Offering::Offered, classes()),
+            )
+            .unwrap();
+        store
+            .save_classes(
+                &MC202,
+                &semester,
+                &ClassesEntry::scraped("there", Offering::Offered, classes()[1..].to_vec()),
+            )
+            .unwrap();
+        store.save_credits(&MC202, 6).unwrap();
+        store.save_prerequisites(&MC202, &prerequisites).unwrap();
+
+        let entry = store.load_classes(&MC202, &semester).unwrap().unwrap();
+        assert_eq!(entry.source.as_deref(), Some("there"));
+        assert!(entry.scraped_at.is_some());
+        assert_eq!(entry.classes.len(), 1);
+        assert_eq!(entry.classes[0].code, "B");
+        assert_eq!(store.load_credits(&MC202), Ok(Some(6)));
+        assert_eq!(store.load_prerequisites(&MC202), Ok(Some(prerequisites)));
+    }
+
+    #[test]
+    fn imports_yaml_contents() {
+        let store = SqliteStore::open(Path::new(":memory:")).unwrap();
+        let entry = ClassesEntry {
+            scraped_at: None,
+            source: None,
+            offering: Offering::Offered,
+            error: None,
+            classes: classes(),
+        };
+        let failed = ClassesEntry {
+            offering: Offering::Unknown,
+            error: Some("HTTP 503".to_string()),
+            ..ClassesEntry::scraped("there", Offering::Unknown, vec![])
+        };
+        let contents = CacheContents {
+            classes: HashMap::from([(
+                "MC202".to_string(),
+                HashMap::from([
+                    ("2s2023".to_string(), entry),
+                    ("1s2024".to_string(), failed),
+                ]),
+            )]),
+            credits: HashMap::from([("MC202".to_string(), 6), ("MC102".to_string(), 6)]),
+            prerequisites: HashMap::from([("MC102".to_string(), Prerequisites::default())]),
+        };
+        assert_eq!(store.import(&contents), Ok(()));
+        assert_eq!(store.contents(), Ok(contents));
+
+        let loaded = store
+            .load_classes(&MC202, &Semester::from("2s2023"))
+            .unwrap()
+            .unwrap();
+        let classes = classes();
+        assert_eq!(loaded.scraped_at, None);
+        assert_eq!(loaded.classes.len(), 2);
+        assert_eq!(loaded.classes[0].professors, classes[0].professors);
+        assert_eq!(loaded.classes[0].vacancies, Some(60));
+        assert_eq!(loaded.classes[0].slots, classes[0].slots);
+        assert_eq!(loaded.classes[0].rooms(), ["CB01"]);
+        assert_eq!(store.load_credits(&MC202), Ok(Some(6)));
+        assert_eq!(
+            store.conn.query_row(
+                "SELECT COUNT(*) FROM offerings WHERE year = 2023 AND term = 2",
+                [],
+                |row| row.get::<_, i64>(0)
+            ),
+            Ok(1)
+        );
+    }
+
+    #[test]
+    fn upgrades_version_1() {
+        let path =
+            std::env::temp_dir().join(format!("unicamp_planner_{}.sqlite3", std::process::id()));
+        let conn = Connection::open(&path).unwrap();
+        conn.execute_batch(
+            "CREATE TABLE subjects (code TEXT PRIMARY KEY, institute TEXT, credits INTEGER,
+                 prerequisites TEXT);
+             CREATE TABLE offerings (id INTEGER PRIMARY KEY, subject TEXT NOT NULL,
+                 semester TEXT NOT NULL, year INTEGER NOT NULL, term INTEGER NOT NULL,
+                 scraped_at TEXT, source TEXT, UNIQUE (subject, semester));
+             CREATE TABLE classes (id INTEGER PRIMARY KEY, offering INTEGER NOT NULL,
+                 position INTEGER NOT NULL, turma TEXT NOT NULL, vacancies INTEGER,
+                 reserved INTEGER);
+             INSERT INTO subjects (code) VALUES ('MC202');
+             INSERT INTO offerings (subject, semester, year, term) VALUES
+                 ('MC202', '1s2024', 2024, 1), ('MC202', '2s2023', 2023, 2);
+             INSERT INTO classes (offering, position, turma) VALUES (2, 0, 'A');
+             PRAGMA user_version = 1;",
+        )
+        .unwrap();
+        drop(conn);
+        let store = SqliteStore::open(&path).unwrap();
+        let offering = |semester| {
+            store
+                .load_classes(&MC202, &Semester::from(semester))
+                .unwrap()
+                .unwrap()
+                .offering
+        };
+        let offerings = (offering("1s2024"), offering("2s2023"));
+        drop(store);
+        std::fs::remove_file(&path).unwrap();
+
+        assert_eq!(offerings, (Offering::Unknown, Offering::Offered));
+    }
+}
@@ -4,13 +4,47 @@ use std::collections::HashMap;
 use std::fmt;
 use time::{Date, Month};
 
-#[derive(Serialize, Deserialize, Debug)]
+#[derive(Debug)]
 pub struct Subject {
     pub code: &'static str,
     pub institute: &'static str,
+    /// Turmas the subject must be taken in. Empty means any turma.
+    pub pinned: Vec<String>,
+    /// Turmas the subject must not be taken in.
+    pub excluded: Vec<String>,
+    /// How much taking the subject matters, 1 unless given. Plans taking more priority in all
+    /// are preferred, and among subjects with as many classes to choose from, those of higher
+    /// priority are taken first.
+    pub priority: u8,
+    /// Whether the plan is finished without the subject.
+    pub optional: bool,
+    /// First semester the subject may be taken in.
+    pub earliest: Option<Semester>,
+    /// Last semester the subject may be taken in.
+    pub latest: Option<Semester>,
 }
 
-#[derive(Serialize, Deserialize, Debug)]
+impl Subject {
+    pub const fn new(institute: &'static str, code: &'static str) -> Self {
+        Subject {
+            code,
+            institute,
+            pinned: Vec::new(),
+            excluded: Vec::new(),
+            priority: 1,
+            optional: false,
+            earliest: None,
+            latest: None,
+        }
+    }
+
+    pub fn allows(&self, class: &Class) -> bool {
+        (self.pinned.is_empty() || self.pinned.contains(&class.code))
+            && !self.excluded.contains(&class.code)
+    }
+}
+
+#[derive(Clone, Serialize, Deserialize, Debug, PartialEq, Eq, PartialOrd, Ord, Hash)]
 pub struct Semester {
     pub year: u16,
     pub semester: u8,
@@ -46,6 +80,27 @@ impl Semester {
     }
 }
 
+impl Semester {
+    /// Parses e.g. "1s2024".
+    pub fn parse(s: &str) -> Option<Self> {
+        let (semester, year) = s.trim().split_once('s')?;
+        if !["1", "2"].contains(&semester) {
+            return None;
+        }
+        Some(Semester {
+            year: year.parse().ok()?,
+            semester: semester.parse().ok()?,
+        })
+    }
+
+    /// Position of `self` in a plan that starts at `start`, if it is not earlier.
+    pub fn index_from(&self, start: &Semester) -> Option<usize> {
+        let idx = (self.year as isize - start.year as isize) * 2 + self.semester as isize
+            - start.semester as isize;
+        usize::try_from(idx).ok()
+    }
+}
+
 impl From<Date> for Semester {
     fn from(dt: Date) -> Self {
         Semester {
@@ -75,13 +130,169 @@ impl fmt::Display for Semester {
     }
 }
 
+/// A single term of a prerequisite expression.
+#[derive(Clone, Debug, PartialEq, Deserialize, Serialize)]
+pub enum Requirement {
+    /// A subject that must be taken in an earlier semester. Partial prerequisites,
+    /// marked with `*` by DAC, may also be taken in the same semester.
+    Subject { code: String, partial: bool },
+    /// A requirement on the progress in the curriculum (AA200, AA4xx...), which the
+    /// planner cannot check and assumes to be met.
+    Progress(String),
+}
+
+/// Alternatives (separated by "/" in DAC pages) of requirements that must all be met.
+#[derive(Clone, Debug, Default, PartialEq, Deserialize, Serialize)]
+pub struct Prerequisites(pub Vec<Vec<Requirement>>);
+
+impl Prerequisites {
+    /// Whether any alternative is met given the planned subjects taken in earlier
+    /// semesters and in the current one. Subjects that are not planned at all are
+    /// assumed to have been taken already.
+    pub fn is_met(&self, planned: &[&str], earlier: &[&str], current: &[&str]) -> bool {
+        self.0.is_empty()
+            || self.0.iter().any(|alternative| {
+                alternative.iter().all(|requirement| match requirement {
+                    Requirement::Subject { code, partial } => {
+                        !planned.contains(&code.as_str())
+                            || earlier.contains(&code.as_str())
+                            || (*partial && current.contains(&code.as_str()))
+                    }
+                    Requirement::Progress(_) => true,
+                })
+            })
+    }
+}
+
+/// Written as in DAC pages, e.g. "MC102 *MA111 / F 128", or "none".
+impl fmt::Display for Prerequisites {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        if self.0.is_empty() {
+            return write!(f, "none");
+        }
+        let alternatives = self.0.iter().map(|alternative| {
+            alternative
+                .iter()
+                .map(|requirement| match requirement {
+                    Requirement::Subject {
+                        code,
+                        partial: true,
+                    } => format!("*{}", code),
+                    Requirement::Subject { code, .. } | Requirement::Progress(code) => {
+                        code.to_string()
+                    }
+                })
+                .join(" ")
+        });
+        write!(f, "{}", alternatives.format(" / "))
+    }
+}
+
+impl From<&str> for Prerequisites {
+    fn from(s: &str) -> Self {
+        Prerequisites(
+            s.split('/')
+                .map(|alternative| {
+                    // Some codes have a space after a single letter, e.g. "F 128"
+                    let mut codes: Vec<String> = vec![];
+                    for token in alternative.split_whitespace() {
+                        match codes.last_mut() {
+                            Some(last)
+                                if last.trim_start_matches('*').len() == 1
+                                    && token.chars().all(|c| c.is_ascii_digit()) =>
+                            {
+                                last.push(' ');
+                                last.push_str(token);
+                            }
+                            _ => codes.push(token.to_string()),
+                        }
+                    }
+                    codes
+                        .into_iter()
+                        .filter(|code| code.chars().any(|c| c.is_ascii_digit()))
+                        .map(|code| match code.strip_prefix('*') {
+                            Some(code) => Requirement::Subject {
+                                code: code.to_string(),
+                                partial: true,
+                            },
+                            None if code.starts_with("AA") => Requirement::Progress(code),
+                            None => Requirement::Subject {
+                                code,
+                                partial: false,
+                            },
+                        })
+                        .collect::<Vec<_>>()
+                })
+                .filter(|alternative| !alternative.is_empty())
+                .collect(),
+        )
+    }
+}
+
+/// Why a subject has no classes in a timesheet.
+#[derive(Clone, Debug, PartialEq)]
+pub enum Exclusion {
+    /// DAC has no classes of the subject in the semester.
+    NotOffered,
+    /// The page could not be fetched, for the given reason if known, so whether the subject is
+    /// offered is not known.
+    Unknown(Option<String>),
+    /// The page could not be parsed.
+    Unparseable(String),
+    /// The subject is not cached and running offline.
+    NotCached,
+    /// Every class is in a turma the plan pins out or excludes.
+    Disallowed,
+}
+
+impl fmt::Display for Exclusion {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Exclusion::NotOffered => write!(f, "not offered"),
+            Exclusion::Unknown(Some(reason)) => write!(f, "could not be fetched ({})", reason),
+            Exclusion::Unknown(None) => write!(f, "not known to be offered"),
+            Exclusion::Unparseable(reason) => write!(f, "page could not be parsed ({})", reason),
+            Exclusion::NotCached => write!(f, "not cached while running offline"),
+            Exclusion::Disallowed => write!(f, "no allowed turma"),
+        }
+    }
+}
+
 #[derive(Clone, Debug, Deserialize, Serialize)]
 pub struct Timesheet<'a> {
     #[serde(borrow)]
     pub table: HashMap<&'a str, Vec<Class>>,
+    /// Subjects without classes, and why.
+    #[serde(skip)]
+    pub excluded: HashMap<&'a str, Exclusion>,
 }
 
 impl<'a> Timesheet<'a> {
+    /// Whether any subject has classes, which is not the case before DAC publishes them.
+    pub fn is_published(&self) -> bool {
+        self.table.values().any(|classes| !classes.is_empty())
+    }
+
+    /// Leaves `subject` without classes for `reason`.
+    pub fn exclude(&mut self, subject: &'a str, reason: Exclusion) {
+        self.table.remove(subject);
+        self.excluded.insert(subject, reason);
+    }
+
+    /// Drops the classes in turmas that `subjects` pin out or exclude.
+    pub fn retain_allowed(&mut self, subjects: &[Subject]) {
+        for subject in subjects {
+            if let Some(classes) = self.table.get_mut(subject.code) {
+                classes.retain(|class| subject.allows(class));
+                if classes.is_empty() {
+                    self.exclude(subject.code, Exclusion::Disallowed);
+                }
+            }
+        }
+    }
+
+    /// Drops classes listed more than once, with the same turma and slots. Turmas at the same
+    /// times are all kept, so that solutions may take any of them.
     pub fn remove_duplicates(&mut self) {
         let mut to_remove;
         for (_, classes) in self.table.iter_mut() {
@@ -92,7 +303,7 @@ impl<'a> Timesheet<'a> {
                 if to_remove.contains(&j) {
                     continue;
                 }
-                if classes[i] == classes[j] {
+                if classes[i] == classes[j] && classes[i].code == classes[j].code {
                     to_remove.push(j);
                 }
             }
@@ -105,13 +316,97 @@ impl<'a> Timesheet<'a> {
     }
 }
 
-#[derive(Clone, Debug, Deserialize, Serialize)]
-pub struct Class(pub Vec<Slot>);
+#[derive(Clone, Debug, Default, Deserialize, Serialize)]
+#[serde(from = "ClassRepr")]
+pub struct Class {
+    pub code: String,
+    pub professors: Vec<String>,
+    pub vacancies: Option<u16>,
+    pub reserved: Option<u16>,
+    pub slots: Vec<Slot>,
+}
+
+/// On-disk representation of a class. Caches written before turma metadata
+/// was scraped hold a bare list of slots per class.
+#[derive(Deserialize)]
+#[serde(untagged)]
+enum ClassRepr {
+    Slots(Vec<Slot>),
+    Full {
+        #[serde(default)]
+        code: String,
+        #[serde(default)]
+        professors: Vec<String>,
+        #[serde(default)]
+        vacancies: Option<u16>,
+        #[serde(default)]
+        reserved: Option<u16>,
+        slots: Vec<Slot>,
+    },
+}
+
+impl From<ClassRepr> for Class {
+    fn from(repr: ClassRepr) -> Self {
+        match repr {
+            ClassRepr::Slots(slots) => Class {
+                slots,
+                ..Default::default()
+            },
+            ClassRepr::Full {
+                code,
+                professors,
+                vacancies,
+                reserved,
+                slots,
+            } => Class {
+                code,
+                professors,
+                vacancies,
+                reserved,
+                slots,
+            },
+        }
+    }
+}
+
+impl Class {
+    pub fn rooms(&self) -> Vec<&str> {
+        self.slots
+            .iter()
+            .filter_map(|slot| slot.room.as_deref())
+            .unique()
+            .collect()
+    }
+}
+
+impl fmt::Display for Class {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        if self.code.is_empty() {
+            write!(f, "turma ?")?;
+        } else {
+            write!(f, "turma {}", self.code)?;
+        }
+        if !self.professors.is_empty() {
+            write!(f, ", professors: {}", self.professors.join("; "))?;
+        }
+        if let Some(vacancies) = self.vacancies {
+            write!(f, ", vacancies: {}", vacancies)?;
+            if let Some(reserved) = self.reserved {
+                write!(f, " ({} reserved)", reserved)?;
+            }
+        }
+        let rooms = self.rooms();
+        if !rooms.is_empty() {
+            write!(f, ", rooms: {}", rooms.join(", "))?;
+        }
+        Ok(())
+    }
+}
 
 impl PartialEq for Class {
     fn eq(&self, other: &Self) -> bool {
-        let mut slots_self = self.0.clone();
-        let mut slots_other = other.0.clone();
+        let mut slots_self = self.slots.clone();
+        let mut slots_other = other.slots.clone();
         if slots_self.len() != slots_other.len() {
             return false;
         }
@@ -126,37 +421,45 @@ impl PartialEq for Class {
     }
 }
 
+/// Portuguese weekday names, in the order of `Slot::weekday`.
+pub const WEEKDAYS: [&str; 7] = [
+    "Domingo", "Segunda", "Terça", "Quarta", "Quinta", "Sexta", "Sábado",
+];
+
 #[derive(Clone, Debug, Deserialize, Serialize)]
 pub struct Slot {
     pub weekday: u8,
     pub start: u16,
     pub finish: u16,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub room: Option<String>,
 }
 
 impl Slot {
-    pub fn new(weekday_pt: String, duration: String) -> Self {
-        let duration_parsed = duration
-            .split('-')
-            .map(|s| {
-                s.split(':')
-                    .map(|s| s.trim().parse::<u16>().unwrap())
-                    .collect::<Vec<_>>()
-            })
-            .collect::<Vec<_>>();
-        Slot {
-            weekday: match weekday_pt.as_str() {
-                "Domingo" => 1,
-                "Segunda" => 2,
-                "Terça" => 3,
-                "Quarta" => 4,
-                "Quinta" => 5,
-                "Sexta" => 6,
-                "Sábado" => 7,
-                s => panic!("Unexpected portuguese weekday {}", s),
-            },
-            start: duration_parsed[0][0] * 100 + duration_parsed[0][1],
-            finish: duration_parsed[1][0] * 100 + duration_parsed[1][1],
+    /// Parses a portuguese weekday and a "HH:MM - HH:MM" duration.
+    pub fn parse(weekday_pt: &str, duration: &str) -> Option<Self> {
+        let mut times = duration.split('-').map(|s| {
+            let (hours, minutes) = s.trim().split_once(':')?;
+            let (hours, minutes) = (hours.parse::<u16>().ok()?, minutes.parse::<u16>().ok()?);
+            if hours > 24 || minutes > 59 {
+                return None;
+            }
+            Some(hours * 100 + minutes)
+        });
+        let (start, finish) = (times.next()??, times.next()??);
+        if times.next().is_some() || start >= finish {
+            return None;
         }
+        Some(Slot {
+            weekday: WEEKDAYS.iter().position(|w| *w == weekday_pt.trim())? as u8 + 1,
+            start,
+            finish,
+            room: None,
+        })
+    }
+
+    pub fn overlaps(&self, other: &Slot) -> bool {
+        self.weekday == other.weekday && self.start < other.finish && self.finish > other.start
     }
 }
 
@@ -213,7 +516,7 @@ impl<'a> From<&HashMap<&'a str, Class>> for Schedule<'a> {
         for weekday in 1..8 {
             for hour in 8..23 {
                 for (code, class) in value.iter() {
-                    for slot in &class.0 {
+                    for slot in &class.slots {
                         if slot.weekday == weekday
                             && slot.start / 100 <= hour
                             && slot.finish / 100 > hour
@@ -295,3 +598,72 @@ impl<'a> Schedule<'a> {
         None
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn remove_duplicates_keeps_turmas_at_the_same_times() {
+        let class = |code: &str| Class {
+            code: code.to_string(),
+            slots: vec![Slot::parse("Segunda", "10:00 - 12:00").unwrap()],
+            ..Default::default()
+        };
+        let mut ts = Timesheet {
+            table: HashMap::from([("MC102", vec![class("A"), class("B"), class("A")])]),
+            excluded: HashMap::new(),
+        };
+        ts.remove_duplicates();
+        let codes = ts.table["MC102"]
+            .iter()
+            .map(|c| c.code.as_str())
+            .collect_vec();
+        assert_eq!(codes, ["A", "B"]);
+    }
+
+    #[test]
+    fn retain_allowed_keeps_pinned_and_drops_excluded_turmas() {
+        let classes = ["A", "B", "C"]
+            .iter()
+            .map(|code| Class {
+                code: code.to_string(),
+                ..Default::default()
+            })
+            .collect::<Vec<_>>();
+        let mut ts = Timesheet {
+            table: HashMap::from([
+                ("MC102", classes.clone()),
+                ("MC202", classes.clone()),
+                ("MC322", classes),
+            ]),
+            excluded: HashMap::new(),
+        };
+        let subjects = [
+            Subject {
+                pinned: vec!["A".to_string(), "C".to_string()],
+                ..Subject::new("IC", "MC102")
+            },
+            Subject {
+                excluded: vec!["B".to_string()],
+                ..Subject::new("IC", "MC202")
+            },
+            Subject {
+                pinned: vec!["B".to_string()],
+                excluded: vec!["B".to_string()],
+                ..Subject::new("IC", "MC322")
+            },
+        ];
+        ts.retain_allowed(&subjects);
+        let codes = |subject| {
+            ts.table[subject]
+                .iter()
+                .map(|c| c.code.as_str())
+                .collect_vec()
+        };
+        assert_eq!(codes("MC102"), ["A", "C"]);
+        assert_eq!(codes("MC202"), ["A", "C"]);
+        assert!(!ts.table.contains_key("MC322"));
+        assert_eq!(ts.excluded["MC322"], Exclusion::Disallowed);
+    }
+}